@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Fake external program for the `command` store, keeping the objects in a local folder.
+# usage: fake_store.sh <folder> put|get|head|delete <object id>
+#        fake_store.sh <folder> list
+
+set -e
+
+folder="$1"
+mkdir -p "$folder"
+
+case "$2" in
+  put)
+    cat > "$folder/.$3.tmp"
+    mv "$folder/.$3.tmp" "$folder/$3"
+    ;;
+  get)
+    cat "$folder/$3"
+    ;;
+  head)
+    if [ -f "$folder/$3" ]; then
+      wc -c < "$folder/$3"
+    fi
+    ;;
+  list)
+    ls "$folder"
+    ;;
+  delete)
+    rm -f "$folder/$3"
+    ;;
+  *)
+    echo "unknown operation: $2" >&2
+    exit 1
+    ;;
+esac
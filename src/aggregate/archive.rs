@@ -0,0 +1,223 @@
+use crate::aggregate::repository::Aggregate;
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Take};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tar::{Builder, Header, HeaderMode};
+
+const BLOCK_SIZE: u64 = 512;
+/// a tar archive ends with two empty blocks
+const END_SIZE: u64 = 2 * BLOCK_SIZE;
+
+/// The layout of a tar archive of files, computed from their metadata only. The files are read
+/// as the archive is, so that it never is in memory as a whole.
+pub struct Archive {
+    entries: Vec<Entry>,
+    size: u64,
+}
+
+pub struct Entry {
+    /// the file's path relative to the root folder, also its name in the archive
+    pub file_path: String,
+    /// offset of the file's data within the archive
+    pub offset: u64,
+    pub length: u64,
+    /// the file's mtime, in seconds since epoch, as crawl records it
+    pub mtime: Option<u64>,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    header: Vec<u8>,
+}
+
+impl Entry {
+    fn padding(&self) -> u64 {
+        (BLOCK_SIZE - self.length % BLOCK_SIZE) % BLOCK_SIZE
+    }
+}
+
+impl Archive {
+    pub fn new(root: &Path, files: &[Aggregate]) -> Result<Self> {
+        let mut entries = Vec::with_capacity(files.len());
+        let mut offset = 0;
+
+        for file in files {
+            let path = root.join(&file.file_path);
+            let metadata = fs::metadata(&path)
+                .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+
+            // the header as `Builder::append_path_with_name` writes it, with the extension
+            // entry of long paths if needed
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            let mut builder = Builder::new(Vec::new());
+            builder
+                .append_data(&mut header, &file.file_path, io::empty())
+                .with_context(|| format!("Failed to add {} to archive", file.file_path))?;
+            let header = std::mem::take(builder.get_mut());
+
+            let entry = Entry {
+                file_path: file.file_path.clone(),
+                offset: offset + header.len() as u64,
+                length: metadata.len(),
+                mtime: crate::file::mtime(&metadata),
+                path,
+                modified: metadata.modified().ok(),
+                header,
+            };
+            offset = entry.offset + entry.length + entry.padding();
+            entries.push(entry);
+        }
+
+        Ok(Self {
+            entries,
+            size: offset + END_SIZE,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn into_reader(self) -> Reader {
+        let mut segments = VecDeque::with_capacity(3 * self.entries.len() + 1);
+        for entry in self.entries {
+            let padding = entry.padding();
+            segments.push_back(Segment::Bytes(entry.header));
+            segments.push_back(Segment::File(entry.path, entry.length, entry.modified));
+            segments.push_back(Segment::Zeros(padding));
+        }
+        segments.push_back(Segment::Zeros(END_SIZE));
+        Reader {
+            segments,
+            current: None,
+        }
+    }
+}
+
+enum Segment {
+    Bytes(Vec<u8>),
+    File(PathBuf, u64, Option<SystemTime>),
+    Zeros(u64),
+}
+
+impl Segment {
+    fn open(self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Segment::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+            Segment::File(path, length, modified) => Box::new(Exact {
+                inner: File::open(&path)?.take(length),
+                path,
+                length,
+                modified,
+            }),
+            Segment::Zeros(length) => Box::new(io::repeat(0).take(length)),
+        })
+    }
+}
+
+/// Reads a file, failing if it is shorter than expected or changed while being read.
+struct Exact {
+    inner: Take<File>,
+    path: PathBuf,
+    length: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Read for Exact {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            if self.inner.limit() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} was truncated since crawled", self.path.display()),
+                ));
+            }
+            let metadata = self.inner.get_ref().metadata()?;
+            if metadata.len() != self.length || metadata.modified().ok() != self.modified {
+                return Err(io::Error::other(format!(
+                    "{} changed while being read",
+                    self.path.display()
+                )));
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Reads the archive, opening each file when reaching it.
+pub struct Reader {
+    segments: VecDeque<Segment>,
+    current: Option<Box<dyn Read + Send>>,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(current) = &mut self.current {
+                let read = current.read(buf)?;
+                if read > 0 {
+                    return Ok(read);
+                }
+            }
+            match self.segments.pop_front() {
+                Some(segment) => self.current = Some(segment.open()?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn same_as_builder() {
+        let root = std::env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()));
+        let long = format!("{}/file.txt", "long".repeat(40));
+        fs::create_dir_all(root.join(&long).parent().unwrap()).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("empty.txt"), "").unwrap();
+        fs::write(root.join(&long), vec![7; 1000]).unwrap();
+        let files = ["a.txt", "empty.txt", &long]
+            .iter()
+            .map(|file_path| Aggregate {
+                aggregate_path: "aggregate.tar".to_string(),
+                file_path: file_path.to_string(),
+                offset: None,
+                length: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut builder = Builder::new(Vec::new());
+        for file in &files {
+            builder
+                .append_path_with_name(root.join(&file.file_path), &file.file_path)
+                .unwrap();
+        }
+        let expected = builder.into_inner().unwrap();
+
+        let archive = Archive::new(&root, &files).unwrap();
+        assert_eq!(archive.size(), expected.len() as u64);
+        for entry in archive.entries() {
+            let data = &expected[entry.offset as usize..(entry.offset + entry.length) as usize];
+            assert_eq!(data, fs::read(root.join(&entry.file_path)).unwrap());
+        }
+        let mut actual = Vec::new();
+        archive.into_reader().read_to_end(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -2,12 +2,16 @@ use anyhow::Result;
 use fallible_iterator::FallibleIterator;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{OptionalExtension, Row};
+use rusqlite::{OptionalExtension, Row, ToSql};
 
 #[derive(Debug)]
 pub struct Aggregate {
     pub aggregate_path: String,
     pub file_path: String,
+    /// offset of the file's data in the aggregate's tar, once pushed
+    pub offset: Option<u64>,
+    /// length of the file's data in the aggregate's tar, once pushed
+    pub length: Option<u64>,
 }
 
 impl From<&Row<'_>> for Aggregate {
@@ -15,6 +19,8 @@ impl From<&Row<'_>> for Aggregate {
         Aggregate {
             aggregate_path: row.get(0).unwrap(),
             file_path: row.get(1).unwrap(),
+            offset: row.get(2).unwrap(),
+            length: row.get(3).unwrap(),
         }
     }
 }
@@ -32,8 +38,25 @@ impl Repository {
         self.pool.get()?.execute(
             include_str!("sql/insert.sql"),
             &[
-                (":aggregate_path", &aggregate.aggregate_path),
+                (":aggregate_path", &aggregate.aggregate_path as &dyn ToSql),
                 (":file_path", &aggregate.file_path),
+                (":offset", &aggregate.offset),
+                (":length", &aggregate.length),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records where the file's data is in the aggregate's tar.
+    pub fn update_layout(&self, aggregate: &Aggregate) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/update_layout.sql"),
+            &[
+                (":aggregate_path", &aggregate.aggregate_path as &dyn ToSql),
+                (":file_path", &aggregate.file_path),
+                (":offset", &aggregate.offset),
+                (":length", &aggregate.length),
             ],
         )?;
 
@@ -1 +1 @@
-select aggregate_path, file_path from aggregates where file_path=:path
\ No newline at end of file
+select aggregate_path, file_path, offset, length from aggregates where file_path=:path
\ No newline at end of file
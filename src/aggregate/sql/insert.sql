@@ -1 +1 @@
-insert into aggregates (aggregate_path, file_path) values (:aggregate_path, :file_path)
\ No newline at end of file
+insert into aggregates (aggregate_path, file_path, offset, length) values (:aggregate_path, :file_path, :offset, :length)
\ No newline at end of file
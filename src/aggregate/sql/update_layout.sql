@@ -0,0 +1 @@
+update aggregates set offset=:offset, length=:length where aggregate_path=:aggregate_path and file_path=:file_path
\ No newline at end of file
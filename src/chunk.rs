@@ -1,20 +1,22 @@
+use crate::chunk_location::repository::{ChunkLocation, Repository as ChunkLocationsRepository};
 use crate::hash::ChunkedSha256;
 use crate::metrics::Metric;
 use crate::status::Status;
 use crate::store::Store;
 use crate::{ChunksRepository, FilesRepository, Pgp};
-use anyhow::{bail, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use std::fmt::{Debug, Formatter};
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use tokio::runtime::Runtime;
 use uuid::Uuid;
 
 pub mod repository;
 
+const VERSION: u8 = 1;
+
 fn sha256(bytes: &[u8]) -> String {
     let mut hasher = sha2::Sha256::new();
     hasher.update(bytes);
@@ -88,7 +90,7 @@ impl Debug for ClearChunk {
 impl ClearChunk {
     pub fn new(uuid: Uuid, metadata: Metadata, payload: Vec<u8>) -> Self {
         Self {
-            version: 1,
+            version: VERSION,
             uuid,
             metadata,
             payload,
@@ -101,12 +103,178 @@ impl ClearChunk {
         pgp.encrypt(&mut bytes.as_slice(), &mut writer)
             .with_context(|| "Failed to encrypt")?;
         Ok(LocalEncryptedChunk {
-            chunk: self,
+            uuid: self.uuid,
+            clear_sha256: self.sha256(),
+            metadata: self.metadata,
+            clear_payload: Some(self.payload),
+            payload: writer,
+            location: None,
+        })
+    }
+
+    /// The chunk as already in the store, at `location`, without encrypting it again.
+    pub fn stored(self, location: ChunkLocation) -> LocalEncryptedChunk {
+        LocalEncryptedChunk {
+            uuid: self.uuid,
+            clear_sha256: self.sha256(),
+            metadata: self.metadata,
+            clear_payload: Some(self.payload),
+            payload: Vec::new(),
+            location: Some(location),
+        }
+    }
+}
+
+/// A chunk whose payload is read while being encrypted, instead of being held in memory. It must
+/// be a whole file. The cipher text is still held in memory until uploaded, as stores take whole
+/// objects.
+pub struct StreamedChunk<R> {
+    uuid: Uuid,
+    metadata: Metadata,
+    payload_size: u64,
+    payload: R,
+}
+
+impl<R: Read> StreamedChunk<R> {
+    pub fn new(uuid: Uuid, metadata: Metadata, payload_size: u64, payload: R) -> Self {
+        Self {
+            uuid,
+            metadata,
+            payload_size,
+            payload,
+        }
+    }
+
+    /// Encrypts the chunk as serialized by [`ClearChunk`]: the same header, followed by the
+    /// payload.
+    pub fn encrypt(self, pgp: &Pgp) -> Result<LocalEncryptedChunk> {
+        let header = bincode::serialize(&(VERSION, &self.metadata, self.payload_size))?;
+        let mut payload = HashingReader {
+            inner: self.payload.take(self.payload_size),
+            hasher: sha2::Sha256::new(),
+            read: 0,
+        };
+        let mut writer = Vec::<u8>::new();
+        pgp.encrypt(&mut Cursor::new(header).chain(&mut payload), &mut writer)
+            .with_context(|| "Failed to encrypt")?;
+        if payload.read != self.payload_size {
+            bail!(
+                "Failed to read: read {} bytes instead of {} bytes",
+                payload.read,
+                self.payload_size
+            );
+        }
+        Ok(LocalEncryptedChunk {
+            uuid: self.uuid,
+            metadata: self.metadata,
+            clear_sha256: format!("{:x}", payload.hasher.finalize()),
+            clear_payload: None,
             payload: writer,
+            location: None,
+        })
+    }
+
+    /// The chunk as already in the store, at `location`, without encrypting it again: its
+    /// payload is only read to compute its sha-256 sum.
+    pub fn stored(self, location: ChunkLocation) -> Result<LocalEncryptedChunk> {
+        let mut payload = HashingReader {
+            inner: self.payload.take(self.payload_size),
+            hasher: sha2::Sha256::new(),
+            read: 0,
+        };
+        std::io::copy(&mut payload, &mut std::io::sink()).with_context(|| "Failed to read")?;
+        if payload.read != self.payload_size {
+            bail!(
+                "Failed to read: read {} bytes instead of {} bytes",
+                payload.read,
+                self.payload_size
+            );
+        }
+        Ok(LocalEncryptedChunk {
+            uuid: self.uuid,
+            metadata: self.metadata,
+            clear_sha256: format!("{:x}", payload.hasher.finalize()),
+            clear_payload: None,
+            payload: Vec::new(),
+            location: Some(location),
         })
     }
 }
 
+/// Where the chunk `uuid` is in `store`, named `store_name`, if an interrupted push already
+/// uploaded it: its location was recorded before the upload, with the size and sha-256 sum of
+/// the cipher text, and the store holds a matching object.
+pub async fn find_stored(
+    uuid: Uuid,
+    store: &dyn Store,
+    store_name: &str,
+    chunk_locations_repository: Arc<ChunkLocationsRepository>,
+) -> Result<Option<ChunkLocation>> {
+    let name = store_name.to_string();
+    let previous = match blocking(move || {
+        chunk_locations_repository
+            .find_by_chunk_uuid_and_store(&uuid, &name)
+            .with_context(|| "Failed to load chunk location")
+    })
+    .await?
+    .filter(|location| location.status == Status::Pending)
+    {
+        Some(previous) => previous,
+        None => return Ok(None),
+    };
+    match store.head(uuid).await {
+        Ok(Some(head))
+            if head.size == previous.size
+                && (head.sha256.is_none()
+                    || previous.sha256.is_none()
+                    || head.sha256 == previous.sha256) =>
+        {
+            log::info!("{}: already in store, skipping upload", uuid);
+            Ok(Some(ChunkLocation {
+                status: Status::Done,
+                etag: head.etag,
+                retain_until: head.retain_until,
+                ..previous
+            }))
+        }
+        Ok(Some(_)) => {
+            log::debug!("{}: differs in store, uploading again", uuid);
+            Ok(None)
+        }
+        Ok(None) => Ok(None),
+        Err(e) => {
+            log::debug!("{}: cannot check store: {:#}", uuid, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Runs the database calls off the runtime's threads.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| anyhow!("Failed to run task: {}", e))?
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+    read: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
 impl Chunk for ClearChunk {
     fn uuid(&self) -> Uuid {
         self.uuid
@@ -138,46 +306,101 @@ impl TryFrom<&Vec<u8>> for ClearChunk {
     type Error = Error;
 
     fn try_from(value: &Vec<u8>) -> Result<Self, Self::Error> {
-        if value[0] != 1 {
-            bail!("Unsupported version: {}", value[0])
+        match value.first() {
+            Some(&VERSION) => {}
+            Some(version) => bail!("Unsupported version: {}", version),
+            None => bail!("Empty chunk"),
         }
         // todo check options
         Ok(bincode::deserialize(value)?)
     }
 }
 
-impl From<LocalEncryptedChunk> for ClearChunk {
-    fn from(chunk: LocalEncryptedChunk) -> Self {
-        chunk.chunk
-    }
-}
-
 pub struct LocalEncryptedChunk {
-    chunk: ClearChunk,
+    uuid: Uuid,
+    metadata: Metadata,
+    /// the sha-256 sum of the clear text payload
+    clear_sha256: String,
+    /// kept to compute the file's sha-256 sum; streamed chunks are whole files
+    clear_payload: Option<Vec<u8>>,
     payload: Vec<u8>,
+    /// the copy in the store, once pushed
+    location: Option<ChunkLocation>,
 }
 
 impl LocalEncryptedChunk {
-    pub fn push(self, store: Arc<Box<dyn Store>>, runtime: Arc<Runtime>) -> Result<Self> {
-        runtime
-            .block_on(store.put(self.chunk.uuid, self.payload.as_slice()))
+    /// Whether the chunk is in the store already, e.g. uploaded by an interrupted push.
+    pub fn is_pushed(&self) -> bool {
+        self.location.is_some()
+    }
+
+    /// Uploads the chunk to `store`, named `store_name`, unless it is already there. The size and
+    /// sha-256 sum of the cipher text are recorded beforehand, so that an object uploaded by an
+    /// interrupted push is found by [`find_stored`] rather than uploaded again.
+    pub async fn push(
+        mut self,
+        store: &dyn Store,
+        store_name: &str,
+        chunk_locations_repository: Arc<ChunkLocationsRepository>,
+    ) -> Result<Self> {
+        if self.location.is_some() {
+            return Ok(self);
+        }
+        let uuid = self.uuid();
+
+        let location = ChunkLocation {
+            chunk_uuid: uuid,
+            store: store_name.to_string(),
+            status: Status::Pending,
+            size: self.payload.len() as u64,
+            etag: None,
+            retain_until: None,
+            sha256: Some(self.sha256()),
+        };
+        let mut location = blocking(move || {
+            chunk_locations_repository
+                .insert(&location)
+                .with_context(|| "Failed to record chunk location")?;
+            Ok(location)
+        })
+        .await?;
+
+        let stored = store
+            .put(uuid, self.payload.as_slice())
+            .await
             .with_context(|| "Failed to upload")?;
+
+        location.status = Status::Done;
+        location.etag = stored.etag;
+        location.retain_until = stored.retain_until;
+        if let Some((size, sha256)) = stored.resumed {
+            log::debug!("{}: pushed the data of a previous run", uuid);
+            location.size = size;
+            location.sha256 = Some(sha256);
+        }
+        self.location = Some(location);
         Ok(self)
     }
 
+    /// Records the chunk as pushed, and the file as done once all its chunks are.
     pub fn finalize(
         self,
         files_repository: Arc<FilesRepository>,
         chunks_repository: Arc<ChunksRepository>,
+        chunk_locations_repository: Arc<ChunkLocationsRepository>,
         hash: Arc<Mutex<ChunkedSha256>>,
         sender: &Sender<Metric>,
     ) -> Result<Self> {
+        let location = self
+            .location
+            .as_ref()
+            .ok_or_else(|| anyhow!("Chunk {} was not pushed", self.uuid()))?;
+        chunk_locations_repository
+            .insert(location)
+            .with_context(|| "Failed to record chunk location")?;
+
         chunks_repository
-            .mark_done(
-                &self.uuid(),
-                &self.chunk.sha256(),
-                self.payload.len() as u64,
-            )
+            .mark_done(&self.uuid(), &self.clear_sha256, location.size)
             .with_context(|| "Failed to finalize chunk")?;
 
         let chunks = chunks_repository
@@ -194,19 +417,29 @@ impl LocalEncryptedChunk {
             .expect("chunks is not empty");
 
         let mut hash = hash.lock().unwrap();
-        hash.update(self.chunk.payload.as_slice(), self.chunk.metadata.idx);
+        if let Some(payload) = &self.clear_payload {
+            hash.update(payload.as_slice(), self.metadata.idx);
+        }
 
         if 0 == chunks
             .iter()
             .filter(|chunk| chunk.status != Status::Done)
             .count()
         {
+            // when pushing to another store, or resuming, this run may not have seen all chunks
             let sha256 = match hash.finalize() {
-                None => {
-                    log::warn!("Failed to compute sha256 of {}", self.chunk.metadata.file);
-                    "".to_string()
-                }
-                Some(sha256) => sha256,
+                _ if self.clear_payload.is_none() => self.clear_sha256.clone(),
+                Some(sha256) if hash.blocks() == self.metadata.total => sha256,
+                _ => match files_repository
+                    .find_by_uuid(&file_uuid)
+                    .with_context(|| "Failed to finalize file")?
+                {
+                    Some(file) if !file.sha256.is_empty() => file.sha256,
+                    _ => {
+                        log::warn!("Failed to compute sha256 of {}", self.metadata.file);
+                        "".to_string()
+                    }
+                },
             };
             let _ = sender.send(Metric::FileProcessed);
 
@@ -222,11 +455,11 @@ impl LocalEncryptedChunk {
 
 impl Chunk for LocalEncryptedChunk {
     fn uuid(&self) -> Uuid {
-        self.chunk.uuid
+        self.uuid
     }
 
     fn metadata(&self) -> &Metadata {
-        &self.chunk.metadata
+        &self.metadata
     }
 
     fn sha256(&self) -> String {
@@ -239,8 +472,11 @@ impl Chunk for LocalEncryptedChunk {
 }
 
 impl EncryptedChunk for LocalEncryptedChunk {
-    fn decrypt(self, _pgp: &Pgp) -> Result<ClearChunk> {
-        Ok(self.chunk)
+    fn decrypt(self, pgp: &Pgp) -> Result<ClearChunk> {
+        match self.clear_payload {
+            Some(payload) => Ok(ClearChunk::new(self.uuid, self.metadata, payload)),
+            None => RemoteEncryptedChunk::from(self.payload).decrypt(pgp),
+        }
     }
 }
 
@@ -259,7 +495,8 @@ impl EncryptedChunk for RemoteEncryptedChunk {
         let mut clear_bytes = Vec::with_capacity(self.payload.len());
         match pgp.decrypt(Cursor::new(self.payload), &mut clear_bytes) {
             Ok(_) => {
-                let chunk = ClearChunk::try_from(&clear_bytes).unwrap();
+                let chunk =
+                    ClearChunk::try_from(&clear_bytes).with_context(|| "Cannot deserialize")?;
                 log::debug!(
                     "{}: decrypted and deserialized chunk {}/{}",
                     chunk.metadata().file(),
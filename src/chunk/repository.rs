@@ -3,7 +3,7 @@ use anyhow::{bail, Result};
 use fallible_iterator::FallibleIterator;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Row;
+use rusqlite::{OptionalExtension, Row, ToSql};
 use uuid::Uuid;
 
 // todo rename fields to better match what thy are
@@ -97,6 +97,38 @@ impl Repository {
         }
     }
 
+    /// Records a failed push attempt. A done chunk stays done, as it failed to be pushed to
+    /// another store.
+    pub fn mark_failed(&self, uuid: &Uuid, error: &str) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/mark_failed.sql"),
+            &[(":uuid", &uuid.to_string()), (":error", &error.to_string())],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_by_file_uuid(&self, file_uuid: &Uuid) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/delete_by_file_uuid.sql"),
+            &[(":file_uuid", &file_uuid.to_string())],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Result<Option<Chunk>> {
+        Ok(self
+            .pool
+            .get()?
+            .query_row(
+                include_str!("sql/find_by_uuid.sql"),
+                &[(":uuid", &uuid.to_string())],
+                |row| Ok(row.into()),
+            )
+            .optional()?)
+    }
+
     pub fn find_by_file_uuid(&self, file_uuid: &Uuid) -> Result<Vec<Chunk>> {
         let connection = self.pool.get()?;
 
@@ -148,6 +180,48 @@ impl Repository {
         Ok(rows.map(|row| Ok(row.into())).collect::<Vec<Chunk>>()?)
     }
 
+    /// Finds the chunks of the file that are not in `store` yet, nor were moved from it.
+    pub fn find_by_file_uuid_missing_in_store(
+        &self,
+        file_uuid: &Uuid,
+        store: &str,
+    ) -> Result<Vec<Chunk>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt =
+            connection.prepare(include_str!("sql/find_by_file_uuid_missing_in_store.sql"))?;
+
+        let rows = stmt.query(&[
+            (":file_uuid", &file_uuid.to_string()),
+            (":store", &store.to_string()),
+        ])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect::<Vec<Chunk>>()?)
+    }
+
+    /// Finds the chunks stored in `store` whose file was last modified before `before` (in seconds
+    /// since epoch). An aggregate is as old as the most recent of its files.
+    pub fn find_by_store_unchanged_before(&self, store: &str, before: u64) -> Result<Vec<Chunk>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt =
+            connection.prepare(include_str!("sql/find_by_store_unchanged_before.sql"))?;
+
+        let rows = stmt.query(&[(":store", &store as &dyn ToSql), (":before", &before)])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect::<Vec<Chunk>>()?)
+    }
+
+    pub fn find_by_status(&self, status: Status) -> Result<Vec<Chunk>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_by_status.sql"))?;
+
+        let rows = stmt.query(&[(":status", Into::<&str>::into(&status))])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect::<Vec<Chunk>>()?)
+    }
+
     pub fn find_siblings_by_uuid(&self, uuid: &Uuid) -> Result<Vec<Chunk>> {
         let connection = self.pool.get()?;
 
@@ -158,6 +232,22 @@ impl Repository {
         Ok(rows.map(|row| Ok(row.into())).collect::<Vec<Chunk>>()?)
     }
 
+    /// Counts the chunks that are not in `store` yet, skipping those of the files that already
+    /// failed `max_attempts` times, if set.
+    pub fn count_missing_in_store(&self, store: &str, max_attempts: Option<u64>) -> Result<u64> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/count_missing_in_store.sql"))?;
+
+        Ok(stmt.query_row(
+            &[
+                (":store", &store as &dyn ToSql),
+                (":max_attempts", &max_attempts),
+            ],
+            |row| row.get::<_, u64>(0),
+        )?)
+    }
+
     pub fn count_by_status(&self, status: Status) -> Result<u64> {
         let connection = self.pool.get()?;
 
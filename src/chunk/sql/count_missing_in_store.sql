@@ -0,0 +1,12 @@
+select count(*)
+from chunks c
+where not exists(select 1
+                 from chunk_locations l
+                 where l.chunk_uuid = c.uuid
+                   and l.store = :store
+                   and l.status in ('DONE', 'MOVED'))
+  and (:max_attempts is null or not exists(select 1
+                                          from files f
+                                          where f.uuid = c.file_uuid
+                                            and f.status = 'FAILED'
+                                            and f.attempts >= :max_attempts))
\ No newline at end of file
@@ -0,0 +1 @@
+delete from chunks where file_uuid=:file_uuid
\ No newline at end of file
@@ -0,0 +1,9 @@
+select uuid, file_uuid, idx, sha256, offset, size, payload_size, status
+from chunks c
+where file_uuid = :file_uuid
+  and not exists(select 1
+                 from chunk_locations l
+                 where l.chunk_uuid = c.uuid
+                   and l.store = :store
+                   and l.status in ('DONE', 'MOVED'))
+order by idx
\ No newline at end of file
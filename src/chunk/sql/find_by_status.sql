@@ -0,0 +1,3 @@
+select uuid, file_uuid, idx, sha256, offset, size, payload_size, status
+from chunks
+where status = :status
\ No newline at end of file
@@ -0,0 +1,13 @@
+select c.uuid, c.file_uuid, c.idx, c.sha256, c.offset, c.size, c.payload_size, c.status
+from chunks c
+         join files f on f.uuid = c.file_uuid
+         join chunk_locations l on l.chunk_uuid = c.uuid
+where l.store = :store
+  and l.status = 'DONE'
+  and case
+          when f.mode = 'AGGREGATE' then (select max(m.mtime)
+                                          from aggregates a
+                                                   join files m on m.path = a.file_path
+                                          where a.aggregate_path = f.path)
+          else f.mtime
+          end < :before
\ No newline at end of file
@@ -2,5 +2,6 @@ update chunks
 set
     sha256=:sha256,
     size=:size,
-    status='DONE'
+    status='DONE',
+    error=null
 where uuid=:uuid
\ No newline at end of file
@@ -0,0 +1,183 @@
+use crate::status::Status;
+use anyhow::Result;
+use fallible_iterator::FallibleIterator;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{OptionalExtension, Row, ToSql};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// A copy of a chunk in a store.
+#[derive(Debug)]
+pub struct ChunkLocation {
+    pub chunk_uuid: Uuid,
+    /// name of the store
+    pub store: String,
+    pub status: Status,
+    /// cipher text length, as stored
+    pub size: u64,
+    pub etag: Option<String>,
+    /// Object Lock retention: the chunk cannot be deleted before that time, in seconds since epoch
+    pub retain_until: Option<u64>,
+    /// the sha-256 sum of the cipher text, as stored
+    pub sha256: Option<String>,
+}
+
+impl ChunkLocation {
+    /// Whether the store still retains the chunk, i.e. refuses to delete it.
+    pub fn is_retained(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("now is after epoch")
+            .as_secs();
+        self.retain_until.map(|until| until > now).unwrap_or(false)
+    }
+}
+
+impl From<&Row<'_>> for ChunkLocation {
+    fn from(row: &Row<'_>) -> Self {
+        ChunkLocation {
+            chunk_uuid: Uuid::parse_str(&row.get::<_, String>(0).unwrap()).unwrap(),
+            store: row.get(1).unwrap(),
+            status: row.get(2).unwrap(),
+            size: row.get(3).unwrap(),
+            etag: row.get(4).unwrap(),
+            retain_until: row.get(5).unwrap(),
+            sha256: row.get(6).unwrap(),
+        }
+    }
+}
+
+pub struct Repository {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Repository {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    /// Inserts or replaces the location, setting its upload time to now.
+    pub fn insert(&self, location: &ChunkLocation) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/insert.sql"),
+            &[
+                (
+                    ":chunk_uuid",
+                    &location.chunk_uuid.to_string() as &dyn ToSql,
+                ),
+                (":store", &location.store),
+                (":status", &location.status),
+                (":size", &location.size),
+                (":etag", &location.etag),
+                (":retain_until", &location.retain_until),
+                (":sha256", &location.sha256),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn find_by_chunk_uuid(&self, chunk_uuid: &Uuid) -> Result<Vec<ChunkLocation>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_by_chunk_uuid.sql"))?;
+
+        let rows = stmt.query(&[(":chunk_uuid", &chunk_uuid.to_string())])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
+    pub fn find_by_chunk_uuid_and_store(
+        &self,
+        chunk_uuid: &Uuid,
+        store: &str,
+    ) -> Result<Option<ChunkLocation>> {
+        Ok(self
+            .pool
+            .get()?
+            .query_row(
+                include_str!("sql/find_by_chunk_uuid_and_store.sql"),
+                &[
+                    (":chunk_uuid", &chunk_uuid.to_string()),
+                    (":store", &store.to_string()),
+                ],
+                |row| Ok(row.into()),
+            )
+            .optional()?)
+    }
+
+    pub fn find_by_store(&self, store: &str) -> Result<Vec<ChunkLocation>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_by_store.sql"))?;
+
+        let rows = stmt.query(&[(":store", store)])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
+    /// Finds the locations in `store` of the chunks planned again since pushed.
+    pub fn find_orphans_by_store(&self, store: &str) -> Result<Vec<ChunkLocation>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_orphans_by_store.sql"))?;
+
+        let rows = stmt.query(&[(":store", store)])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
+    pub fn delete(&self, chunk_uuid: &Uuid, store: &str) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/delete.sql"),
+            &[
+                (":chunk_uuid", &chunk_uuid.to_string()),
+                (":store", &store.to_string()),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn update_status(&self, chunk_uuid: &Uuid, store: &str, status: Status) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/update_status.sql"),
+            &[
+                (":chunk_uuid", &chunk_uuid.to_string()),
+                (":store", &store.to_string()),
+                (":status", &Into::<&str>::into(&status).to_string()),
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(retain_until: Option<u64>) -> ChunkLocation {
+        ChunkLocation {
+            chunk_uuid: Uuid::new_v4(),
+            store: "default".to_string(),
+            status: Status::Done,
+            size: 0,
+            etag: None,
+            retain_until,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn retained() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(!location(None).is_retained());
+        assert!(!location(Some(now - 60)).is_retained());
+        assert!(location(Some(now + 60)).is_retained());
+    }
+}
@@ -0,0 +1,3 @@
+delete
+from chunk_locations
+where chunk_uuid = :chunk_uuid and store = :store
\ No newline at end of file
@@ -0,0 +1,3 @@
+select chunk_uuid, store, status, size, etag, retain_until, sha256
+from chunk_locations
+where store = :store
\ No newline at end of file
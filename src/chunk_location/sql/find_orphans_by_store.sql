@@ -0,0 +1,4 @@
+select chunk_uuid, store, status, size, etag, retain_until, sha256
+from chunk_locations l
+where store = :store
+  and not exists(select 1 from chunks c where c.uuid = l.chunk_uuid)
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert or replace into chunk_locations (chunk_uuid, store, status, size, etag, retain_until, sha256, uploaded_at)
+values (:chunk_uuid, :store, :status, :size, :etag, :retain_until, :sha256, datetime('now'))
\ No newline at end of file
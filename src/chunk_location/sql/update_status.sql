@@ -0,0 +1 @@
+update chunk_locations set status=:status where chunk_uuid = :chunk_uuid and store = :store
\ No newline at end of file
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use fallible_iterator::FallibleIterator;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Row;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct ChunkTransfer {
+    pub destination: String,
+    pub chunk_uuid: Uuid,
+    /// the sha-256 sum of the copied object
+    pub sha256: String,
+}
+
+impl From<&Row<'_>> for ChunkTransfer {
+    fn from(row: &Row<'_>) -> Self {
+        ChunkTransfer {
+            destination: row.get(0).unwrap(),
+            chunk_uuid: Uuid::parse_str(&row.get::<_, String>(1).unwrap()).unwrap(),
+            sha256: row.get(2).unwrap(),
+        }
+    }
+}
+
+pub struct Repository {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Repository {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    pub fn insert(&self, transfer: &ChunkTransfer) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/insert.sql"),
+            &[
+                (":destination", &transfer.destination),
+                (":chunk_uuid", &transfer.chunk_uuid.to_string()),
+                (":sha256", &transfer.sha256),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn find_by_destination(&self, destination: &str) -> Result<Vec<ChunkTransfer>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_by_destination.sql"))?;
+
+        let rows = stmt.query(&[(":destination", destination)])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+}
@@ -0,0 +1,3 @@
+select destination, chunk_uuid, sha256
+from chunk_transfers
+where destination = :destination
\ No newline at end of file
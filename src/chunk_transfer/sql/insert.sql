@@ -0,0 +1,2 @@
+insert or replace into chunk_transfers (destination, chunk_uuid, sha256, transferred_at)
+values (:destination, :chunk_uuid, :sha256, datetime('now'))
\ No newline at end of file
@@ -1,13 +1,18 @@
+use crate::controller::push::{Order, Parallelism, Policy};
+use crate::controller::tier::Rule as TierRule;
+use crate::store::s3_official::StorageClassRule;
 use crate::store::StoreKind;
 use crate::Error;
 use anyhow::{anyhow, bail, Result};
 use byte_unit::Byte;
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use yaml_rust::yaml::Array;
 use yaml_rust::{Yaml, YamlLoader};
 
+#[derive(Clone)]
 pub struct Config {
     file: String,
     yaml: Yaml,
@@ -15,6 +20,7 @@ pub struct Config {
 
 const MAX_CHUNK_SIZE: &str = "1GB";
 const DEFAULT_CHUNK_SIZE: &str = "100MB";
+const DEFAULT_STORE_NAME: &str = "default";
 
 impl Config {
     pub fn new(file: &str) -> Result<Self> {
@@ -88,6 +94,53 @@ impl Config {
         self.yaml["queue_size"].as_i64().unwrap_or_default().max(0) as usize
     }
 
+    /// How many chunks each stage of push processes at once. Encryption and upload default to
+    /// `workers`.
+    pub fn get_push_parallelism(&self) -> Parallelism {
+        let get = |stage: &str, default: usize| {
+            self.yaml["push"][stage]
+                .as_i64()
+                .map(|count| count.max(1) as usize)
+                .unwrap_or(default)
+        };
+        Parallelism {
+            readers: get("readers", 2),
+            encryptors: get("encryptors", self.get_max_workers_count()),
+            uploaders: get("uploaders", self.get_max_workers_count()),
+            finalizers: get("finalizers", 1),
+            queue_size: self.get_max_queue_size(),
+        }
+    }
+
+    /// The order in which push pushes files.
+    pub fn get_push_policy(&self) -> Result<Policy> {
+        let mut priorities = GlobSetBuilder::new();
+        for glob in self.yaml["push"]["priorities"]
+            .as_vec()
+            .unwrap_or(&Array::new())
+            .iter()
+            .map(|item| item.as_str().unwrap_or_default())
+        {
+            priorities.add(Glob::new(glob)?);
+        }
+        Ok(Policy {
+            order: match self.yaml["push"]["order"].as_str() {
+                Some(order) => Order::try_from(order).map_err(|e| {
+                    anyhow!("Unable to load configuration from {}: {}", self.file, e)
+                })?,
+                None => Order::Crawl,
+            },
+            priorities: priorities.build()?,
+        })
+    }
+
+    /// Memory that chunks in flight in push may use at most.
+    pub fn get_memory_limit(&self) -> Option<Byte> {
+        self.yaml["memory_limit"]
+            .as_str()
+            .map(|size| Byte::from_str(size).unwrap())
+    }
+
     pub fn get_database_path(&self) -> Result<&str> {
         self.yaml["database"].as_str().ok_or_else(|| {
             anyhow!(
@@ -119,21 +172,308 @@ impl Config {
         Ok(globs.build()?)
     }
 
+    /// The name under which chunk locations in the store are recorded.
+    pub fn get_store_name(&self) -> &str {
+        self.yaml["store"]["name"]
+            .as_str()
+            .unwrap_or(DEFAULT_STORE_NAME)
+    }
+
+    /// Returns a configuration where `store` is the store named `name`: either `store` itself or
+    /// one of the `stores`.
+    pub fn with_store(&self, name: &str) -> Result<Config> {
+        if name == self.get_store_name() {
+            return Ok(self.clone());
+        }
+
+        let mut store = match &self.yaml["stores"][name] {
+            Yaml::Hash(store) => store.clone(),
+            _ => bail!(
+                "Unable to load configuration from {}: `stores.{}` is missing",
+                self.file,
+                name
+            ),
+        };
+        store.insert(Yaml::String("name".into()), Yaml::String(name.into()));
+
+        let mut config = self.clone();
+        if let Yaml::Hash(yaml) = &mut config.yaml {
+            yaml.insert(Yaml::String("store".into()), Yaml::Hash(store));
+        }
+        Ok(config)
+    }
+
+    /// The names of the additional `stores`.
+    pub fn get_stores_names(&self) -> Vec<&str> {
+        self.yaml["stores"]
+            .as_hash()
+            .map(|stores| stores.keys().filter_map(|name| name.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_tier_rules(&self) -> Result<Vec<TierRule<'_>>> {
+        let mut rules = Vec::new();
+        for rule in self.yaml["tiers"]
+            .as_vec()
+            .map(|rules| rules.as_slice())
+            .unwrap_or_default()
+        {
+            let mandatory = |key: &str| {
+                rule[key].as_str().ok_or_else(|| {
+                    anyhow!(
+                        "Unable to load configuration from {}: `tiers[].{}` key is mandatory",
+                        self.file,
+                        key
+                    )
+                })
+            };
+            rules.push(TierRule {
+                from: mandatory("from")?,
+                to: mandatory("to")?,
+                unchanged_for: Duration::from_secs(
+                    rule["unchanged_days"].as_i64().unwrap_or_default().max(0) as u64 * 86400,
+                ),
+            });
+        }
+        Ok(rules)
+    }
+
     pub fn get_store_type(&self) -> Result<StoreKind> {
-        let store = self.yaml["store"]["type"].as_str().unwrap_or("log");
+        self.parse_store_type(
+            "store.type",
+            self.yaml["store"]["type"].as_str().unwrap_or("log"),
+        )
+    }
+
+    /// The type of the store wrapped by the chaos store.
+    pub fn get_chaos_store_type(&self) -> Result<StoreKind> {
+        self.parse_store_type(
+            "store.chaos.type",
+            self.yaml["store"]["chaos"]["type"]
+                .as_str()
+                .unwrap_or("memory"),
+        )
+    }
+
+    fn parse_store_type(&self, key: &str, store: &str) -> Result<StoreKind> {
         match store {
+            "azure-blob" => Ok(StoreKind::AzureBlob),
+            "chaos" => Ok(StoreKind::Chaos),
+            "command" => Ok(StoreKind::Command),
+            "gcs" => Ok(StoreKind::Gcs),
             "log" => Ok(StoreKind::Log),
+            "memory" => Ok(StoreKind::Memory),
             "s3" => Ok(StoreKind::S3),
             "s3-official" => Ok(StoreKind::S3Official),
             "local" => Ok(StoreKind::Local),
+            "sftp" => Ok(StoreKind::Sftp),
+            "webdav" => Ok(StoreKind::WebDav),
             _ => bail!(
-                "Unable to load configuration from {}: `store.type` {} is invalid",
+                "Unable to load configuration from {}: `{}` {} is invalid",
                 self.file,
+                key,
                 store
             ),
         }
     }
 
+    pub fn get_chaos_latency(&self) -> Duration {
+        Duration::from_millis(
+            self.yaml["store"]["chaos"]["latency"]
+                .as_i64()
+                .unwrap_or_default()
+                .max(0) as u64,
+        )
+    }
+
+    pub fn get_chaos_error_rate(&self) -> f64 {
+        self.get_chaos_rate("error_rate")
+    }
+
+    pub fn get_chaos_forbidden_rate(&self) -> f64 {
+        self.get_chaos_rate("forbidden_rate")
+    }
+
+    pub fn get_chaos_truncate_rate(&self) -> f64 {
+        self.get_chaos_rate("truncate_rate")
+    }
+
+    pub fn get_chaos_corrupt_rate(&self) -> f64 {
+        self.get_chaos_rate("corrupt_rate")
+    }
+
+    pub fn get_chaos_seed(&self) -> Option<u64> {
+        self.yaml["store"]["chaos"]["seed"]
+            .as_i64()
+            .map(|seed| seed as u64)
+    }
+
+    fn get_chaos_rate(&self, key: &str) -> f64 {
+        self.yaml["store"]["chaos"][key]
+            .as_f64()
+            .unwrap_or_default()
+            .clamp(0.0, 1.0)
+    }
+
+    pub fn get_command_store_program(&self) -> Result<&str> {
+        self.yaml["store"]["command"]["program"]
+            .as_str()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unable to load configuration from {}: `store.command.program` key is mandatory",
+                    self.file
+                )
+            })
+    }
+
+    pub fn get_command_store_args(&self) -> Vec<&str> {
+        self.yaml["store"]["command"]["args"]
+            .as_vec()
+            .map(|args| args.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|arg| arg.as_str())
+            .collect()
+    }
+
+    pub fn get_azure_blob_account(&self) -> Result<&str> {
+        self.yaml["store"]["azure-blob"]["account"]
+            .as_str()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unable to load configuration from {}: `store.azure-blob.account` key is mandatory",
+                    self.file
+                )
+            })
+    }
+
+    pub fn get_azure_blob_container(&self) -> Result<&str> {
+        self.yaml["store"]["azure-blob"]["container"]
+            .as_str()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unable to load configuration from {}: `store.azure-blob.container` key is mandatory",
+                    self.file
+                )
+            })
+    }
+
+    pub fn get_azure_blob_endpoint(&self) -> Option<&str> {
+        self.yaml["store"]["azure-blob"]["endpoint"].as_str()
+    }
+
+    pub fn get_azure_blob_prefix(&self) -> Option<&str> {
+        self.yaml["store"]["azure-blob"]["prefix"].as_str()
+    }
+
+    pub fn get_azure_blob_access_key(&self) -> Option<&str> {
+        self.yaml["store"]["azure-blob"]["access_key"].as_str()
+    }
+
+    pub fn get_azure_blob_sas_token(&self) -> Option<&str> {
+        self.yaml["store"]["azure-blob"]["sas_token"].as_str()
+    }
+
+    pub fn get_azure_blob_block_size(&self) -> u64 {
+        self.yaml["store"]["azure-blob"]["block_size"]
+            .as_str()
+            .map(|b| Byte::from_str(b).unwrap().get_bytes() as u64)
+            .unwrap_or_default()
+    }
+
+    pub fn get_gcs_bucket(&self) -> Result<&str> {
+        self.yaml["store"]["gcs"]["bucket"].as_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to load configuration from {}: `store.gcs.bucket` key is mandatory",
+                self.file
+            )
+        })
+    }
+
+    pub fn get_gcs_endpoint(&self) -> Option<&str> {
+        self.yaml["store"]["gcs"]["endpoint"].as_str()
+    }
+
+    pub fn get_gcs_prefix(&self) -> Option<&str> {
+        self.yaml["store"]["gcs"]["prefix"].as_str()
+    }
+
+    pub fn get_gcs_credentials(&self) -> Option<&str> {
+        self.yaml["store"]["gcs"]["credentials"].as_str()
+    }
+
+    pub fn get_gcs_resumable_chunk_size(&self) -> u64 {
+        self.yaml["store"]["gcs"]["resumable_chunk_size"]
+            .as_str()
+            .map(|b| Byte::from_str(b).unwrap().get_bytes() as u64)
+            .unwrap_or_default()
+    }
+
+    pub fn get_sftp_host(&self) -> Result<&str> {
+        self.yaml["store"]["sftp"]["host"].as_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to load configuration from {}: `store.sftp.host` key is mandatory",
+                self.file
+            )
+        })
+    }
+
+    pub fn get_sftp_port(&self) -> Result<Option<u16>> {
+        match self.yaml["store"]["sftp"]["port"].as_i64() {
+            None => Ok(None),
+            Some(port @ 1..=65535) => Ok(Some(port as u16)),
+            Some(port) => bail!(
+                "Unable to load configuration from {}: `store.sftp.port` {} is invalid",
+                self.file,
+                port
+            ),
+        }
+    }
+
+    pub fn get_sftp_username(&self) -> Option<&str> {
+        self.yaml["store"]["sftp"]["username"].as_str()
+    }
+
+    pub fn get_sftp_key_file(&self) -> Option<&str> {
+        self.yaml["store"]["sftp"]["key_file"].as_str()
+    }
+
+    pub fn get_sftp_path(&self) -> Result<&str> {
+        self.yaml["store"]["sftp"]["path"].as_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to load configuration from {}: `store.sftp.path` key is mandatory",
+                self.file
+            )
+        })
+    }
+
+    pub fn get_webdav_url(&self) -> Result<&str> {
+        self.yaml["store"]["webdav"]["url"].as_str().ok_or_else(|| {
+            anyhow!(
+                "Unable to load configuration from {}: `store.webdav.url` key is mandatory",
+                self.file
+            )
+        })
+    }
+
+    pub fn get_webdav_username(&self) -> Option<&str> {
+        self.yaml["store"]["webdav"]["username"].as_str()
+    }
+
+    pub fn get_webdav_password(&self) -> Option<&str> {
+        self.yaml["store"]["webdav"]["password"].as_str()
+    }
+
+    pub fn get_webdav_ca_certificate(&self) -> Option<&str> {
+        self.yaml["store"]["webdav"]["ca_certificate"].as_str()
+    }
+
+    pub fn get_webdav_accept_invalid_certs(&self) -> bool {
+        self.yaml["store"]["webdav"]["accept_invalid_certs"]
+            .as_bool()
+            .unwrap_or(false)
+    }
+
     pub fn get_local_store_path(&self) -> Result<&str> {
         self.yaml["store"]["local"]["path"].as_str().ok_or_else(|| {
             anyhow!(
@@ -169,6 +509,28 @@ impl Config {
         })
     }
 
+    pub fn get_s3_endpoint(&self) -> Option<&str> {
+        self.yaml["store"]["s3"]["endpoint"].as_str()
+    }
+
+    pub fn get_s3_path_style(&self) -> bool {
+        self.yaml["store"]["s3"]["path_style"]
+            .as_bool()
+            .unwrap_or(false)
+    }
+
+    pub fn get_s3_prefix(&self) -> Option<&str> {
+        self.yaml["store"]["s3"]["prefix"].as_str()
+    }
+
+    pub fn get_s3_sse(&self) -> Option<&str> {
+        self.yaml["store"]["s3"]["sse"].as_str()
+    }
+
+    pub fn get_s3_sse_kms_key_id(&self) -> Option<&str> {
+        self.yaml["store"]["s3"]["sse_kms_key_id"].as_str()
+    }
+
     pub fn get_s3_official_bucket(&self) -> Result<&str> {
         self.yaml["store"]["s3-official"]["bucket"]
             .as_str()
@@ -180,10 +542,103 @@ impl Config {
             })
     }
 
+    pub fn get_s3_official_endpoint(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["endpoint"].as_str()
+    }
+
+    pub fn get_s3_official_region(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["region"].as_str()
+    }
+
+    pub fn get_s3_official_prefix(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["prefix"].as_str()
+    }
+
+    pub fn get_s3_official_sse(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["sse"].as_str()
+    }
+
+    pub fn get_s3_official_sse_kms_key_id(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["sse_kms_key_id"].as_str()
+    }
+
     pub fn get_s3_official_multipart_part_size(&self) -> u64 {
         self.yaml["store"]["s3-official"]["multipart_part_size"]
             .as_str()
             .map(|b| Byte::from_str(b).unwrap().get_bytes() as u64)
             .unwrap_or_default()
     }
+
+    pub fn get_s3_official_multipart_concurrency(&self) -> usize {
+        self.yaml["store"]["s3-official"]["multipart_concurrency"]
+            .as_i64()
+            .unwrap_or(4)
+            .max(1) as usize
+    }
+
+    pub fn get_s3_official_multipart_retries(&self) -> u32 {
+        self.yaml["store"]["s3-official"]["multipart_retries"]
+            .as_i64()
+            .unwrap_or(3)
+            .max(0) as u32
+    }
+
+    pub fn get_s3_official_multipart_spool(&self) -> Option<PathBuf> {
+        self.yaml["store"]["s3-official"]["multipart_spool"]
+            .as_str()
+            .map(PathBuf::from)
+    }
+
+    pub fn get_s3_official_storage_class(&self) -> &str {
+        self.yaml["store"]["s3-official"]["storage_class"]
+            .as_str()
+            .unwrap_or("STANDARD")
+    }
+
+    pub fn get_s3_official_storage_class_rules(&self) -> Result<Vec<StorageClassRule<'_>>> {
+        let mut rules = Vec::new();
+        for rule in self.yaml["store"]["s3-official"]["storage_class_rules"]
+            .as_vec()
+            .map(|rules| rules.as_slice())
+            .unwrap_or_default()
+        {
+            rules.push(StorageClassRule {
+                min_size: rule["min_size"]
+                    .as_str()
+                    .map(|b| Byte::from_str(b).unwrap().get_bytes() as u64)
+                    .unwrap_or_default(),
+                storage_class: rule["storage_class"].as_str().ok_or_else(|| {
+                    anyhow!(
+                        "Unable to load configuration from {}: `store.s3-official.storage_class_rules[].storage_class` key is mandatory",
+                        self.file
+                    )
+                })?,
+            });
+        }
+        Ok(rules)
+    }
+
+    pub fn get_s3_official_restore_days(&self) -> i32 {
+        self.yaml["store"]["s3-official"]["restore"]["days"]
+            .as_i64()
+            .unwrap_or(1)
+            .max(1) as i32
+    }
+
+    pub fn get_s3_official_object_lock_mode(&self) -> Option<&str> {
+        self.yaml["store"]["s3-official"]["object_lock"]["mode"].as_str()
+    }
+
+    pub fn get_s3_official_object_lock_days(&self) -> u32 {
+        self.yaml["store"]["s3-official"]["object_lock"]["days"]
+            .as_i64()
+            .unwrap_or(1)
+            .max(1) as u32
+    }
+
+    pub fn get_s3_official_restore_tier(&self) -> &str {
+        self.yaml["store"]["s3-official"]["restore"]["tier"]
+            .as_str()
+            .unwrap_or("Standard")
+    }
 }
@@ -1,6 +1,10 @@
+pub mod backup;
 pub mod crawl;
 pub mod json;
 pub mod ls;
 pub mod mount;
 pub mod push;
+pub mod restore;
+pub mod tier;
+pub mod transfer;
 pub mod unwrap;
@@ -0,0 +1,41 @@
+use crate::controller::{crawl, push};
+use crate::store::Store;
+use crate::{Pgp, PooledSqliteConnectionManager};
+use anyhow::{anyhow, Context, Result};
+use std::sync::mpsc;
+use std::thread;
+use tokio::runtime::Runtime;
+
+pub struct Config<'a> {
+    pub crawl: crawl::Config<'a>,
+    pub push: push::Config<'a>,
+}
+
+/// Crawls and pushes at the same time: files are pushed as soon as crawl plans their chunks. As
+/// both record their progress in the database, an interrupted backup resumes where it stopped.
+pub fn execute(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    pgp: Pgp,
+    store: Box<dyn Store>,
+    runtime: Runtime,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let crawl_sqlite = sqlite.clone();
+
+    thread::scope(|scope| {
+        let crawl = thread::Builder::new()
+            .name("crawl".to_string())
+            .spawn_scoped(scope, move || {
+                crawl::stream(config.crawl, crawl_sqlite, sender)
+            })
+            .with_context(|| "Failed to start crawl")?;
+
+        let pushed = push::stream(config.push, sqlite, pgp, store, runtime, receiver);
+        let crawled = crawl.join().map_err(|_| anyhow!("Crawl panicked"))?;
+
+        crawled
+            .with_context(|| "Failed to crawl")
+            .and(pushed.with_context(|| "Failed to push"))
+    })
+}
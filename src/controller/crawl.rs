@@ -11,6 +11,7 @@ use globset::GlobSet;
 use std::fs;
 use std::fs::ReadDir;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -23,14 +24,33 @@ pub struct Config<'a> {
 }
 
 struct CurrentAggregate {
+    uuid: Uuid,
     path: String,
     size: u64,
 }
 
 pub fn execute(config: Config, sqlite: PooledSqliteConnectionManager) -> Result<()> {
+    crawl(config, sqlite, None).execute()
+}
+
+/// Crawls, sending the UUID of each chunked file and aggregate as soon as it is ready to be
+/// pushed. Stops when nobody receives them any more.
+pub fn stream(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    ready: Sender<Uuid>,
+) -> Result<()> {
+    crawl(config, sqlite, Some(ready)).execute()
+}
+
+fn crawl(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    ready: Option<Sender<Uuid>>,
+) -> Crawl {
     Crawl {
         root_folder: config.root_folder,
-        root_path: PathBuf::from(config.root_folder).as_path(),
+        root_path: Path::new(config.root_folder),
         chunk_size: config.chunk_size,
         aggregate_min_size: config.aggregate_min_size,
         aggregate_size: config.aggregate_size,
@@ -40,8 +60,9 @@ pub fn execute(config: Config, sqlite: PooledSqliteConnectionManager) -> Result<
         aggregates_repository: AggregatesRepository::new(sqlite.clone()),
         fs_repository: FsRepository::new(sqlite),
         current_aggregate: None,
+        ready,
+        stopped: false,
     }
-    .execute()
 }
 
 struct Crawl<'a> {
@@ -56,6 +77,8 @@ struct Crawl<'a> {
     fs_repository: FsRepository,
     aggregates_repository: AggregatesRepository,
     current_aggregate: Option<CurrentAggregate>,
+    ready: Option<Sender<Uuid>>,
+    stopped: bool,
 }
 
 impl<'a> Crawl<'a> {
@@ -71,6 +94,9 @@ impl<'a> Crawl<'a> {
 
     fn visit_dir(&mut self, path: &Path, dir: ReadDir) {
         for file in dir {
+            if self.stopped {
+                return;
+            }
             match file {
                 Err(e) => log::error!("Failed to read entry in {}: {:#}", path.display(), e),
                 Ok(entry) => {
@@ -122,13 +148,32 @@ impl<'a> Crawl<'a> {
         } else {
             Mode::Chunked
         };
+        let mtime = crate::file::mtime(metadata);
 
-        let db_file = match self
+        // whether the file's chunks are planned in this crawl
+        let (db_file, planned) = match self
             .files_repository
             .find_by_path(local_path.as_os_str().to_str().unwrap())
             .with_context(|| "Failed to load files from database")?
         {
-            Some(db_file) => db_file,
+            Some(db_file) if matches!(db_file.mode, Mode::Chunked) && db_file.changed(metadata) => {
+                let db_file = DbFile {
+                    size: metadata.len(),
+                    chunks: chunks_count,
+                    mtime,
+                    ..db_file
+                };
+                let planned = self.replan(&db_file)?;
+                (db_file, planned)
+            }
+            Some(db_file) => {
+                if let (None, Some(mtime)) = (db_file.mtime, mtime) {
+                    self.files_repository
+                        .update_mtime(&db_file.uuid, mtime)
+                        .with_context(|| "Failed to update file in database")?;
+                }
+                (db_file, false)
+            }
             None => {
                 let db_file = DbFile {
                     uuid: Uuid::new_v4(),
@@ -137,6 +182,7 @@ impl<'a> Crawl<'a> {
                     sha256: "".into(),
                     chunks: chunks_count,
                     mode,
+                    mtime,
                 };
                 self.files_repository
                     .insert(&db_file)
@@ -153,7 +199,7 @@ impl<'a> Crawl<'a> {
                         e
                     );
                 };
-                db_file
+                (db_file, true)
             }
         };
         log::info!(
@@ -164,11 +210,49 @@ impl<'a> Crawl<'a> {
             chunks_count
         );
 
-        if metadata.len() >= self.aggregate_min_size {
-            self.large_file(db_file, metadata.len(), chunks_count)
-        } else {
-            self.small_file(db_file, metadata.len())
+        match db_file.mode {
+            Mode::Chunked => {
+                let uuid = db_file.uuid;
+                self.large_file(db_file, metadata.len(), chunks_count)?;
+                if planned {
+                    self.ready(uuid);
+                }
+                Ok(())
+            }
+            _ => self.small_file(db_file, metadata.len()),
+        }
+    }
+
+    fn ready(&mut self, uuid: Uuid) {
+        if let Some(ready) = &self.ready {
+            if ready.send(uuid).is_err() {
+                log::info!("Push stopped; stopping crawl");
+                self.stopped = true;
+            }
+        }
+    }
+
+    /// Plans the chunks of a file that changed since it was crawled again, unless it is already
+    /// done.
+    fn replan(&self, db_file: &DbFile) -> Result<bool> {
+        if !self
+            .files_repository
+            .replan(db_file)
+            .with_context(|| "Failed to update file in database")?
+        {
+            log::debug!("{}: changed since pushed; skipping", db_file.path);
+            return Ok(false);
         }
+        log::info!(
+            "{}: changed since crawled; planning chunks again",
+            db_file.path
+        );
+
+        // the locations of the chunks already pushed are kept, for push to delete their objects
+        self.chunks_repository
+            .delete_by_file_uuid(&db_file.uuid)
+            .with_context(|| "Failed to delete chunks from database")?;
+        Ok(true)
     }
 
     fn large_file(&self, db_file: DbFile, filesize: u64, chunks_count: u64) -> Result<()> {
@@ -227,6 +311,8 @@ impl<'a> Crawl<'a> {
             .insert(&Aggregate {
                 aggregate_path: aggregate,
                 file_path: db_file.path,
+                offset: None,
+                length: None,
             })
             .with_context(|| "Failed to save aggregate information")
             .and(Ok(()))
@@ -245,6 +331,8 @@ impl<'a> Crawl<'a> {
                 sha256: "".to_string(),
                 chunks: 1,
                 mode: Mode::Aggregate,
+                // aggregates are as old as the most recent of their files
+                mtime: None,
             };
             file_repository
                 .insert(&db_file)
@@ -265,11 +353,14 @@ impl<'a> Crawl<'a> {
                 .with_context(|| "Failed to save aggregate chunk in database")?;
 
             Ok(CurrentAggregate {
+                uuid: db_file.uuid,
                 path: db_file.path,
                 size: filesize,
             })
         }
 
+        // the aggregate that gets no more files, if any
+        let mut full = None;
         match &mut self.current_aggregate {
             None => {
                 self.current_aggregate = Some(new_aggregate(
@@ -280,6 +371,7 @@ impl<'a> Crawl<'a> {
             }
             Some(a) => {
                 if a.size + filesize > self.aggregate_size {
+                    full = Some(a.uuid);
                     self.current_aggregate = Some(new_aggregate(
                         &self.files_repository,
                         &self.chunks_repository,
@@ -291,6 +383,9 @@ impl<'a> Crawl<'a> {
             }
         }
 
+        if let Some(uuid) = full {
+            self.ready(uuid);
+        }
         Ok(self.current_aggregate.as_ref().unwrap().path.clone())
     }
 }
@@ -24,6 +24,7 @@ impl From<(&File, Vec<Chunk>)> for JsonFile {
             sha256: file.sha256.clone(),
             chunks: chunks.iter().map(JsonChunk::from).collect(),
             mode: Into::<&str>::into(&file.mode).to_string(),
+            mtime: file.mtime,
         }
     }
 }
@@ -36,6 +37,8 @@ struct JsonFile {
     sha256: String,
     chunks: Vec<JsonChunk>,
     mode: String,
+    #[serde(default)]
+    mtime: Option<u64>,
 }
 
 impl From<&Chunk> for JsonChunk {
@@ -135,6 +138,7 @@ pub mod import {
             size: file.size,
             chunks: file.chunks.len() as u64,
             mode: Mode::try_from(file.mode.as_str()).unwrap(),
+            mtime: file.mtime,
         };
 
         files_repository
@@ -3,8 +3,31 @@ use crate::file::Mode;
 use crate::PooledSqliteConnectionManager;
 use anyhow::{Context, Result};
 
-pub fn execute(sqlite: PooledSqliteConnectionManager) -> Result<()> {
-    for file in Repository::new(sqlite)
+pub struct Config {
+    /// only list the files that failed to be pushed
+    pub failed: bool,
+}
+
+pub fn execute(config: Config, sqlite: PooledSqliteConnectionManager) -> Result<()> {
+    let repository = Repository::new(sqlite);
+
+    if config.failed {
+        for file in repository
+            .find_failed()
+            .with_context(|| "Unable to find failed files in database")?
+        {
+            println!(
+                "{}\t{} attempt(s), last at {}: {}",
+                file.path,
+                file.attempts,
+                file.failed_at.unwrap_or_default(),
+                file.error.unwrap_or_default()
+            );
+        }
+        return Ok(());
+    }
+
+    for file in repository
         .find_by_mode(vec![Mode::Chunked, Mode::Aggregated])
         .with_context(|| "Unable to find files in database")?
     {
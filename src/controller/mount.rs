@@ -38,15 +38,7 @@ pub fn execute(
         std::fs::create_dir_all(path)?;
     }
 
-    let fs = Fs2CloudFS {
-        cache: config.cache_folder.map(PathBuf::from),
-        fs_repository: FsRepository::new(sqlite.clone()),
-        files_repository: FilesRepository::new(sqlite.clone()),
-        chunks_repository: ChunksRepository::new(sqlite),
-        pgp: Arc::new(pgp),
-        store: Arc::new(store),
-        runtime: Arc::new(runtime),
-    };
+    let fs = Fs2CloudFS::new(config.cache_folder, sqlite, pgp, store, runtime);
 
     let fs_handle = fuser::spawn_mount2(fs, PathBuf::from(config.mountpoint), &options)
         .with_context(|| "Unable to start FS thread")?;
@@ -62,7 +54,7 @@ pub fn execute(
     Ok(())
 }
 
-struct Fs2CloudFS {
+pub(crate) struct Fs2CloudFS {
     cache: Option<PathBuf>,
     fs_repository: FsRepository,
     files_repository: FilesRepository,
@@ -239,7 +231,25 @@ impl Filesystem for Fs2CloudFS {
 }
 
 impl Fs2CloudFS {
-    fn read_from_store(&self, chunk: &DbChunk) -> Result<Vec<u8>> {
+    pub(crate) fn new(
+        cache_folder: Option<&str>,
+        sqlite: PooledSqliteConnectionManager,
+        pgp: Pgp,
+        store: Box<dyn Store>,
+        runtime: Runtime,
+    ) -> Self {
+        Fs2CloudFS {
+            cache: cache_folder.map(PathBuf::from),
+            fs_repository: FsRepository::new(sqlite.clone()),
+            files_repository: FilesRepository::new(sqlite.clone()),
+            chunks_repository: ChunksRepository::new(sqlite),
+            pgp: Arc::new(pgp),
+            store: Arc::new(store),
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    pub(crate) fn read_from_store(&self, chunk: &DbChunk) -> Result<Vec<u8>> {
         self.read_from_cache(&chunk.uuid)
             .map(Ok)
             .unwrap_or_else(|| {
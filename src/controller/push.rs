@@ -1,25 +1,52 @@
-use crate::aggregate::repository::{Aggregate, Repository as AggregatesRepository};
+use crate::aggregate::archive::Archive;
+use crate::aggregate::repository::Repository as AggregatesRepository;
 use crate::chunk::repository::{Chunk as DbChunk, Repository as ChunksRepository};
-use crate::chunk::{Chunk, ClearChunk, Metadata};
+use crate::chunk_location::repository::Repository as ChunkLocationsRepository;
 use crate::file::repository::{File as DbFile, Repository as FilesRepository};
 use crate::file::Mode;
 use crate::hash::ChunkedSha256;
 use crate::metrics::{Collector, Metric};
 use crate::status::Status;
-use crate::store::Store;
-use crate::{Pgp, PooledSqliteConnectionManager, ThreadPool};
-use anyhow::{anyhow, bail, Context, Result};
-use std::collections::HashMap;
+use crate::store::retention::Retained;
+use crate::store::{Store, Unsupported};
+use crate::{Pgp, PooledSqliteConnectionManager};
+use anyhow::{anyhow, bail, Context, Error, Result};
+use byte_unit::Byte;
+use order::Key;
+use pipeline::{Pipeline, Progress, Shared, Source, Task};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Cursor, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
-use tar::Builder;
+use std::time::Instant;
 use tokio::runtime::Runtime;
 use uuid::Uuid;
 
+mod order;
+mod pipeline;
+mod quota;
+
+pub use order::{Order, Policy};
+pub use pipeline::Parallelism;
+pub use quota::{parse_duration, Quota};
+
 pub struct Config<'a> {
     pub root_folder: &'a str,
+    /// name of the store, under which the chunk locations are recorded
+    pub store: &'a str,
+    /// skip the files that already failed that many times; by default, failed files are retried
+    pub max_attempts: Option<u64>,
+    /// set when interrupted: no more chunks are queued, in flight ones are finished
+    pub interrupted: Arc<AtomicBool>,
+    pub parallelism: Parallelism,
+    /// bytes of chunks in flight at once, if limited
+    pub memory_limit: Option<u64>,
+    /// the order of the files to push; `stream` pushes the files as they are received, and only
+    /// what is left afterwards in that order
+    pub policy: Policy,
+    pub quota: Quota,
 }
 
 pub fn execute(
@@ -27,41 +54,251 @@ pub fn execute(
     sqlite: PooledSqliteConnectionManager,
     pgp: Pgp,
     store: Box<dyn Store>,
-    thread_pool: ThreadPool,
     runtime: Runtime,
 ) -> Result<()> {
-    Push {
-        root_folder: config.root_folder,
+    push(config, sqlite, pgp, store, runtime).execute()
+}
+
+/// Pushes the chunked files and aggregates as they are received, whatever the policy, then
+/// whatever is left to push, like `execute` does.
+pub fn stream(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    pgp: Pgp,
+    store: Box<dyn Store>,
+    runtime: Runtime,
+    ready: Receiver<Uuid>,
+) -> Result<()> {
+    push(config, sqlite, pgp, store, runtime).stream(ready)
+}
+
+fn push(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    pgp: Pgp,
+    store: Box<dyn Store>,
+    runtime: Runtime,
+) -> Push {
+    let collector = Collector::new();
+    let store = Arc::new(store);
+    let failed_files = Arc::new(Mutex::new(HashSet::new()));
+    let shared = Arc::new(Shared {
+        pgp,
+        store: store.clone(),
+        store_name: config.store.to_string(),
         files_repository: Arc::new(FilesRepository::new(sqlite.clone())),
         chunks_repository: Arc::new(ChunksRepository::new(sqlite.clone())),
-        aggregates_repository: AggregatesRepository::new(sqlite),
-        pgp: Arc::new(pgp),
-        store: Arc::new(store),
-        thread_pool,
+        chunk_locations_repository: Arc::new(ChunkLocationsRepository::new(sqlite.clone())),
+        aggregates_repository: Arc::new(AggregatesRepository::new(sqlite.clone())),
+        metrics: collector.sender(),
+        failed_files: failed_files.clone(),
+        interrupted: config.interrupted.clone(),
+        pushed: AtomicU64::new(0),
+        progress: Arc::new(Progress::default()),
+    });
+    let pipeline = Pipeline::start(
+        shared.clone(),
+        &config.parallelism,
+        config.memory_limit,
+        &runtime,
+    );
+
+    Push {
+        root_folder: config.root_folder,
+        store_name: config.store.to_string(),
+        max_attempts: config.max_attempts,
+        files_repository: shared.files_repository.clone(),
+        chunks_repository: shared.chunks_repository.clone(),
+        aggregates_repository: shared.aggregates_repository.clone(),
+        store,
         hashes: HashMap::new(),
-        collector: Collector::new(),
-        runtime: Arc::new(runtime),
+        failed_files,
+        queued: HashSet::new(),
+        collector,
+        runtime,
+        interrupted: config.interrupted,
+        policy: config.policy,
+        quota: config.quota,
+        started: Instant::now(),
+        quota_reached: None,
+        shared,
+        pipeline,
     }
-    .execute()
+}
+
+/// What a push would do.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub files: u64,
+    pub chunks: u64,
+    pub aggregates: u64,
+    /// clear text bytes to encrypt and upload
+    pub bytes: u64,
+    /// source files that are missing or cannot be read
+    pub unreadable: u64,
+}
+
+/// Reports which files, chunks and aggregates a push would read, without encrypting anything nor
+/// touching the store.
+pub fn dry_run(config: Config, sqlite: PooledSqliteConnectionManager) -> Result<Plan> {
+    let files_repository = FilesRepository::new(sqlite.clone());
+    let chunks_repository = ChunksRepository::new(sqlite.clone());
+    let aggregates_repository = AggregatesRepository::new(sqlite);
+    let readable = |path: &str| -> bool {
+        match File::open(Path::new(config.root_folder).join(path)) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("unreadable: {}: {}", path, e);
+                false
+            }
+        }
+    };
+    let mut plan = Plan::default();
+
+    for file in files_repository
+        .find_by_mode_missing_in_store(Mode::Chunked, config.store, config.max_attempts)
+        .with_context(|| "Failed to load chunked files")?
+    {
+        if !readable(&file.path) {
+            plan.unreadable += 1;
+            continue;
+        }
+        let chunks = chunks_repository
+            .find_by_file_uuid_missing_in_store(&file.uuid, config.store)
+            .with_context(|| format!("Failed to load chunks of {}", file.path))?;
+        let bytes = chunks.iter().map(|chunk| chunk.payload_size).sum::<u64>();
+        println!(
+            "file: {}: {}/{} chunks, {}",
+            file.path,
+            chunks.len(),
+            file.chunks,
+            Byte::from_bytes(bytes as u128).get_appropriate_unit(false)
+        );
+        plan.files += 1;
+        plan.chunks += chunks.len() as u64;
+        plan.bytes += bytes;
+    }
+
+    for aggregate in files_repository
+        .find_by_mode_missing_in_store(Mode::Aggregate, config.store, config.max_attempts)
+        .with_context(|| "Failed to load aggregate files")?
+    {
+        let files = aggregates_repository
+            .find_by_aggregate_path(&aggregate.path)
+            .with_context(|| format!("Failed to load files of {}", aggregate.path))?;
+        let unreadable = files
+            .iter()
+            .filter(|file| !readable(&file.file_path))
+            .count() as u64;
+        if unreadable > 0 {
+            plan.unreadable += unreadable;
+            continue;
+        }
+        let bytes = Archive::new(Path::new(config.root_folder), &files)
+            .with_context(|| format!("Failed to create {}", aggregate.path))?
+            .size();
+        println!(
+            "aggregate: {}: {} files, {}",
+            aggregate.path,
+            files.len(),
+            Byte::from_bytes(bytes as u128).get_appropriate_unit(false)
+        );
+        plan.aggregates += 1;
+        plan.files += files.len() as u64;
+        plan.chunks += 1;
+        plan.bytes += bytes;
+    }
+
+    println!(
+        "{} files in {} chunks ({} aggregates), {} to encrypt and upload to `{}`; {} files unreadable",
+        plan.files,
+        plan.chunks,
+        plan.aggregates,
+        Byte::from_bytes(plan.bytes as u128).get_appropriate_unit(false),
+        config.store,
+        plan.unreadable
+    );
+
+    Ok(plan)
 }
 
 struct Push<'a> {
     root_folder: &'a str,
+    store_name: String,
+    max_attempts: Option<u64>,
     files_repository: Arc<FilesRepository>,
     chunks_repository: Arc<ChunksRepository>,
-    aggregates_repository: AggregatesRepository,
-    pgp: Arc<Pgp>,
+    aggregates_repository: Arc<AggregatesRepository>,
     store: Arc<Box<dyn Store>>,
-    thread_pool: ThreadPool,
     hashes: HashMap<Uuid, Arc<Mutex<ChunkedSha256>>>,
+    /// files found failed in this run, so that their attempts are counted once
+    failed_files: Arc<Mutex<HashSet<Uuid>>>,
+    /// chunks queued in this run, so that none is pushed twice
+    queued: HashSet<Uuid>,
     collector: Collector,
-    runtime: Arc<Runtime>,
+    runtime: Runtime,
+    interrupted: Arc<AtomicBool>,
+    policy: Policy,
+    quota: Quota,
+    started: Instant,
+    /// why the quota stopped the push, if it did
+    quota_reached: Option<String>,
+    shared: Arc<Shared>,
+    pipeline: Pipeline,
 }
 
 impl<'a> Push<'a> {
-    fn execute(&mut self) -> Result<()> {
+    fn stream(mut self, ready: Receiver<Uuid>) -> Result<()> {
+        log::info!(
+            "Pushing to store `{}` as files are crawled...",
+            self.store_name
+        );
+        self.clean_up();
+
+        for uuid in ready {
+            if self.stopped() {
+                break;
+            }
+            match self
+                .files_repository
+                .find_by_uuid(&uuid)
+                .with_context(|| "Failed to load file")?
+            {
+                Some(file) if matches!(file.mode, Mode::Chunked) => {
+                    self.process_chunked_file(&file)
+                }
+                Some(file) => self.process_aggregate(&file),
+                None => log::warn!("File {} not found", uuid),
+            }
+        }
+
+        self.push_missing()
+    }
+
+    fn execute(self) -> Result<()> {
+        self.clean_up();
+        self.push_missing()
+    }
+
+    /// Gets rid of what previous pushes left in the store.
+    fn clean_up(&self) {
+        if let Err(e) = self.runtime.block_on(self.store.cleanup()) {
+            log::warn!("Failed to clean up store: {:#}", e);
+        }
+        if let Err(e) = self.delete_orphans() {
+            log::warn!("Failed to delete orphan chunks: {:#}", e);
+        }
+    }
+
+    /// Pushes whatever is not in the store yet.
+    fn push_missing(mut self) -> Result<()> {
+        log::info!("Pushing to store `{}`...", self.store_name);
+
         let _ = self.collector.sender().send(Metric::ChunksTotal(
-            match self.chunks_repository.count_by_status(Status::Pending) {
+            match self
+                .chunks_repository
+                .count_missing_in_store(&self.store_name, self.max_attempts)
+            {
                 Ok(count) => count,
                 Err(e) => {
                     log::warn!("Failed to fetch total chunks count: {:#}", e);
@@ -69,198 +306,322 @@ impl<'a> Push<'a> {
                 }
             },
         ));
-        let _ = self.collector.sender().send(Metric::FilesTotal(
-            match self.files_repository.count_by_status(Status::Pending) {
-                Ok(count) => count,
-                Err(e) => {
-                    log::warn!("Failed to fetch total files count: {:#}", e);
-                    0
-                }
-            },
-        ));
-        let _ = self.collector.sender().send(Metric::BytesTotal(
-            match self.files_repository.count_bytes_by_status(Status::Pending) {
-                Ok(count) => count,
-                Err(e) => {
-                    log::warn!("Failed to fetch total bytes count: {:#}", e);
-                    0
-                }
-            },
-        ));
-
-        self.process_chunked_files()
-            .with_context(|| "Failed to process chunked files")?;
+        let (files, bytes) = match self
+            .files_repository
+            .count_missing_in_store(&self.store_name, self.max_attempts)
+        {
+            Ok(counts) => counts,
+            Err(e) => {
+                log::warn!("Failed to fetch total files and bytes count: {:#}", e);
+                (0, 0)
+            }
+        };
+        let _ = self.collector.sender().send(Metric::FilesTotal(files));
+        let _ = self.collector.sender().send(Metric::BytesTotal(bytes));
 
-        self.process_aggregated_files()
-            .with_context(|| "Failed to process aggregated files")?;
+        self.process_files()
+            .with_context(|| "Failed to process files")?;
 
-        Ok(())
+        self.finish()
     }
 
-    fn process_chunked_files(&mut self) -> Result<()> {
-        log::info!("Processing chunked files...");
-
-        for db_file in self
-            .files_repository
-            .find_by_status_and_mode(Status::Pending, Mode::Chunked)
-            .with_context(|| "Failed to load chunked files")?
+    /// Deletes from the store the chunks of the files planned again since pushed. The objects
+    /// still retained are kept, and deleted by a later push; all are kept if the store cannot
+    /// delete objects.
+    fn delete_orphans(&self) -> Result<()> {
+        let chunk_locations_repository = &self.shared.chunk_locations_repository;
+        for location in chunk_locations_repository
+            .find_orphans_by_store(&self.store_name)
+            .with_context(|| "Failed to load chunk locations")?
         {
-            if let Err(e) = File::open(&self.absolute_path(&db_file.path))
-                .with_context(|| "Failed to open")
-                .map(|mut file| {
-                    self.chunks_repository
-                        .find_by_file_uuid_and_status(&db_file.uuid, Status::Pending)
-                        .with_context(|| "Failed to load chunks")
-                        .and_then(|chunks| {
-                            for chunk in chunks {
-                                self.process_chunk(&mut file, &db_file, &chunk)
-                                    .with_context(|| {
-                                        format!(
-                                            "Failed to process chunk {}/{}",
-                                            chunk.idx + 1,
-                                            db_file.chunks
-                                        )
-                                    })?;
-                            }
-                            Ok(())
-                        })
-                })
-            {
-                log::error!("Failed to process chunked file {}: {:#}", db_file.path, e);
+            let uuid = location.chunk_uuid;
+            if location.status != Status::Moved {
+                match self.runtime.block_on(self.store.delete(uuid)) {
+                    Ok(()) => log::debug!("{}: orphan deleted", uuid),
+                    Err(e) if Retained::is(&e) => {
+                        log::debug!("{}: {}", uuid, e);
+                        continue;
+                    }
+                    Err(e) if Unsupported::is(&e) => {
+                        log::info!("{}; orphans are kept in `{}`", e, self.store_name);
+                        return Ok(());
+                    }
+                    // a pending upload may have never reached the store
+                    Err(e) => match self.runtime.block_on(self.store.head(uuid)) {
+                        Ok(None) => {}
+                        _ => {
+                            log::warn!("{}: failed to delete orphan: {:#}", uuid, e);
+                            continue;
+                        }
+                    },
+                }
             }
+            chunk_locations_repository
+                .delete(&uuid, &self.store_name)
+                .with_context(|| "Failed to delete chunk location")?;
         }
+        Ok(())
+    }
+
+    /// Waits for the chunks in flight and, if stopped early, reports what is left to push.
+    fn finish(self) -> Result<()> {
+        let Push {
+            pipeline,
+            runtime,
+            collector,
+            interrupted,
+            quota_reached,
+            ..
+        } = self;
+        pipeline.finish(&runtime);
+        drop(collector);
 
+        let reason = match quota_reached {
+            _ if interrupted.load(Ordering::SeqCst) => "Interrupted".to_string(),
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        let chunks = self
+            .chunks_repository
+            .count_missing_in_store(&self.store_name, self.max_attempts)
+            .with_context(|| "Failed to count chunks left")?;
+        let (files, bytes) = self
+            .files_repository
+            .count_missing_in_store(&self.store_name, self.max_attempts)
+            .with_context(|| "Failed to count files left")?;
+        println!(
+            "{}: {} chunks pushed to `{}`; {} chunks of {} files ({}) left, push again to resume",
+            reason,
+            self.shared.pushed.load(Ordering::SeqCst),
+            self.store_name,
+            chunks,
+            files,
+            Byte::from_bytes(bytes as u128).get_appropriate_unit(false)
+        );
         Ok(())
     }
 
-    fn absolute_path(&self, path: &str) -> PathBuf {
-        let mut path_buf = PathBuf::from(self.root_folder);
-        path_buf.push(path);
-        path_buf
+    /// Whether no more chunks are to be queued, as interrupted or as the quota is reached.
+    fn stopped(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst) || self.quota_reached.is_some()
     }
 
-    fn process_aggregated_files(&mut self) -> Result<()> {
-        log::info!("Processing aggregate files...");
-        let aggregates = self
+    fn process_files(&mut self) -> Result<()> {
+        log::info!("Processing files...");
+
+        let mut files = self
             .files_repository
-            .find_by_status_and_mode(Status::Pending, Mode::Aggregate)
-            .with_context(|| "Failed to load aggregate files")?;
-
-        for aggregate in aggregates {
-            if let Err(e) = self
-                .aggregates_repository
-                .find_by_aggregate_path(&aggregate.path)
-                .with_context(|| "Failed to load aggregated files")
-                .and_then(|files| {
-                    self.create_archive(files)
-                        .with_context(|| "Failed to create aggregate")
-                })
-                .and_then(|data| {
-                    log::debug!("Archive size: {}", data.len());
-
-                    let mut chunk = self
-                        .chunks_repository
-                        .find_by_file_uuid_and_index(&aggregate.uuid, 0)
-                        .with_context(|| "Failed to find chunk 1/1")?
-                        .ok_or_else(|| anyhow!("Failed to find chunk 1/1"))?;
-
-                    chunk.payload_size = data.len() as u64;
-
-                    self.chunks_repository
-                        .update(&chunk)
-                        .with_context(|| "Failed to update payload size of chunk 1/1")?;
-                    self.process_chunk(&mut Cursor::new(data), &aggregate, &chunk)
-                        .with_context(|| "Failed to process chunk 1/1")
-                })
-            {
-                log::error!(
-                    "Failed to process aggregate file {}: {:#}",
-                    aggregate.path,
-                    e
-                );
+            .find_by_mode_missing_in_store(Mode::Chunked, &self.store_name, self.max_attempts)
+            .with_context(|| "Failed to load chunked files")?;
+        files.extend(
+            self.files_repository
+                .find_by_mode_missing_in_store(Mode::Aggregate, &self.store_name, self.max_attempts)
+                .with_context(|| "Failed to load aggregate files")?,
+        );
+        if !self.policy.is_default() {
+            let mut keyed = files
+                .into_iter()
+                .map(|file| (self.key(&file), file))
+                .collect::<Vec<_>>();
+            self.policy.sort(&mut keyed);
+            files = keyed.into_iter().map(|(_, file)| file).collect();
+        }
+
+        for file in files {
+            if self.stopped() {
+                break;
+            }
+            match file.mode {
+                Mode::Chunked => self.process_chunked_file(&file),
+                _ => self.process_aggregate(&file),
             }
         }
 
         Ok(())
     }
 
-    fn create_archive(&mut self, files: Vec<Aggregate>) -> Result<Vec<u8>> {
-        let mut archive = Builder::new(Vec::new());
-
-        for file in files {
-            let path = self.absolute_path(&file.file_path);
-
-            log::debug!(
-                "Archive: adding {} as {}",
-                path.display(),
-                PathBuf::from(&file.file_path).display()
-            );
+    /// The key the file is sorted by; an aggregate's is computed from its files.
+    fn key(&self, file: &DbFile) -> Key {
+        if !matches!(file.mode, Mode::Aggregate) {
+            return self.policy.key(&file.path, file.size, file.mtime);
+        }
+        match self
+            .aggregates_repository
+            .find_by_aggregate_path(&file.path)
+            .and_then(|aggregates| {
+                let mut key = None::<Key>;
+                for aggregate in aggregates {
+                    if let Some(file) = self.files_repository.find_by_path(&aggregate.file_path)? {
+                        let file_key = self.policy.key(&file.path, file.size, file.mtime);
+                        key = Some(match key {
+                            Some(key) => key.merge(file_key),
+                            None => file_key,
+                        });
+                    }
+                }
+                Ok(key)
+            }) {
+            Ok(Some(key)) => key,
+            Ok(None) => self.policy.key(&file.path, 0, None),
+            Err(e) => {
+                log::warn!("Failed to load files of {}: {:#}", file.path, e);
+                self.policy.key(&file.path, 0, None)
+            }
+        }
+    }
 
-            archive
-                .append_path_with_name(path, PathBuf::from(&file.file_path))
-                .with_context(|| format!("Failed to add {} to archive", file.file_path))?;
+    fn process_chunked_file(&mut self, db_file: &DbFile) {
+        let path = self.absolute_path(&db_file.path);
+        if let Err(e) = File::open(&path)
+            .with_context(|| "Failed to open")
+            .and_then(|file| unchanged(db_file, &file))
+            .and_then(|_| {
+                self.chunks_repository
+                    .find_by_file_uuid_missing_in_store(&db_file.uuid, &self.store_name)
+                    .with_context(|| "Failed to load chunks")
+            })
+            .and_then(|chunks| {
+                for chunk in chunks {
+                    if self.stopped() {
+                        break;
+                    }
+                    let source = Source::File {
+                        path: path.clone(),
+                        file: db_file.clone(),
+                        offset: chunk.offset,
+                    };
+                    self.queue(db_file, &chunk, source).with_context(|| {
+                        format!("Failed to queue chunk {}/{}", chunk.idx + 1, db_file.chunks)
+                    })?;
+                }
+                Ok(())
+            })
+        {
+            log::error!("Failed to process chunked file {}: {:#}", db_file.path, e);
+            self.mark_failed(db_file, &e);
         }
+    }
 
-        archive
-            .finish()
-            .with_context(|| "Failed to finalize archive")?;
+    fn mark_failed(&self, file: &DbFile, error: &Error) {
+        if !self.failed_files.lock().unwrap().insert(file.uuid) {
+            return;
+        }
+        if let Err(e) = self
+            .files_repository
+            .mark_failed(&file.uuid, &format!("{:#}", error))
+        {
+            log::warn!("Failed to record failure of {}: {:#}", file.path, e);
+        }
+    }
 
-        Ok(archive.into_inner().expect("read from memory"))
+    fn absolute_path(&self, path: &str) -> PathBuf {
+        let mut path_buf = PathBuf::from(self.root_folder);
+        path_buf.push(path);
+        path_buf
     }
 
-    fn process_chunk<R>(&mut self, source: &mut R, file: &DbFile, chunk: &DbChunk) -> Result<()>
-    where
-        R: Read + Seek,
-    {
-        source
-            .seek(SeekFrom::Start(chunk.offset))
-            .with_context(|| "Failed to seek")?;
-
-        let mut data = vec![0; chunk.payload_size as usize];
-        if chunk.payload_size as usize > source.read(&mut data).with_context(|| "Failed to read")? {
-            // fixme this is not an error, we need to deal with it
-            bail!(
-                "Failed to read: read {} bytes instead of {} bytes",
-                data.len(),
-                chunk.payload_size
+    fn process_aggregate(&mut self, aggregate: &DbFile) {
+        if let Err(e) = self
+            .chunks_repository
+            .find_by_file_uuid_and_index(&aggregate.uuid, 0)
+            .with_context(|| "Failed to find chunk 1/1")
+            .and_then(|chunk| chunk.ok_or_else(|| anyhow!("Failed to find chunk 1/1")))
+            .and_then(|mut chunk| {
+                if self.queued.contains(&chunk.uuid) {
+                    return Ok(());
+                }
+                let archive = self
+                    .aggregates_repository
+                    .find_by_aggregate_path(&aggregate.path)
+                    .with_context(|| "Failed to load aggregated files")
+                    .and_then(|files| {
+                        Archive::new(Path::new(self.root_folder), &files)
+                            .with_context(|| "Failed to create aggregate")
+                    })?;
+                log::debug!("Archive size: {}", archive.size());
+                self.record_aggregated(&archive)?;
+
+                chunk.payload_size = archive.size();
+
+                self.chunks_repository
+                    .update(&chunk)
+                    .with_context(|| "Failed to update payload size of chunk 1/1")?;
+                self.queue(aggregate, &chunk, Source::Archive(archive))
+                    .with_context(|| "Failed to process chunk 1/1")
+            })
+        {
+            log::error!(
+                "Failed to process aggregate file {}: {:#}",
+                aggregate.path,
+                e
             );
+            self.mark_failed(aggregate, &e);
         }
+    }
 
-        self.hashes
-            .entry(file.uuid)
-            .or_insert_with(|| Arc::new(Mutex::new(ChunkedSha256::new())));
+    /// Records the size and mtime of the aggregated files that changed since crawled: the archive
+    /// holds their current content, and fails if they change again while it is read.
+    fn record_aggregated(&self, archive: &Archive) -> Result<()> {
+        for entry in archive.entries() {
+            let file = self
+                .files_repository
+                .find_by_path(&entry.file_path)
+                .with_context(|| format!("Failed to load {}", entry.file_path))?
+                .ok_or_else(|| anyhow!("Failed to load {}: not found", entry.file_path))?;
+            if file.differs(entry.length, entry.mtime) {
+                log::info!("{}: changed since crawled; pushing it as is", file.path);
+                self.files_repository
+                    .replan(&DbFile {
+                        size: entry.length,
+                        mtime: entry.mtime,
+                        ..file
+                    })
+                    .with_context(|| format!("Failed to update {}", entry.file_path))?;
+            }
+        }
+        Ok(())
+    }
 
-        let chunk = ClearChunk::new(
-            chunk.uuid,
-            Metadata::new(file.path.clone(), chunk.idx, file.chunks),
-            data,
-        );
-        let pgp = self.pgp.clone();
-        let store = self.store.clone();
-        let files_repository = self.files_repository.clone();
-        let chunks_repository = self.chunks_repository.clone();
-        let hash = self.hashes.get(&file.uuid).unwrap().clone();
-        let sender = self.collector.sender();
-        let runtime = self.runtime.clone();
-        self.thread_pool.execute(move || {
-            log::debug!("process chunk: {:?}", chunk);
-            let bytes = chunk.payload().len() as u64;
-            let idx = chunk.metadata().idx();
-            let file = chunk.metadata().file().to_string();
-
-            match chunk.encrypt(&pgp).and_then(|chunk| {
-                chunk
-                    .push(store, runtime)
-                    .and_then(|c| c.finalize(files_repository, chunks_repository, hash, &sender))
-            }) {
-                Ok(_) => {
-                    let _ = sender.send(Metric::ChunkProcessed);
-                    let _ = sender.send(Metric::BytesTransferred(bytes));
-                }
-                Err(e) => {
-                    log::error!("Failed to process chunk {} of {}: {:#}", idx, file, e)
-                }
+    /// Sends the chunk through the pipeline, unless it already was in this run.
+    fn queue(&mut self, file: &DbFile, chunk: &DbChunk, source: Source) -> Result<()> {
+        if self.queued.contains(&chunk.uuid) {
+            return Ok(());
+        }
+        let progress = &self.shared.progress;
+        let exceeded = loop {
+            let (uploaded, reserved) = progress.bytes();
+            match self
+                .quota
+                .exceeded(self.started, uploaded + reserved, chunk.payload_size)
+            {
+                // the chunks in flight may turn out to be stored already, or fail
+                Some(_) if reserved > 0 && self.quota.bytes.is_some() => progress.wait(reserved),
+                exceeded => break exceeded,
             }
-        })
+        };
+        if let Some(reason) = exceeded {
+            log::info!("{}; no more chunks are queued", reason);
+            self.quota_reached = Some(reason);
+            return Ok(());
+        }
+        self.queued.insert(chunk.uuid);
+        let hash = self
+            .hashes
+            .entry(file.uuid)
+            .or_insert_with(|| Arc::new(Mutex::new(ChunkedSha256::new())))
+            .clone();
+
+        self.pipeline.queue(Task::new(file, chunk, hash), source)
+    }
+}
+
+/// Fails if the file changed since crawled; crawl then plans its chunks again.
+fn unchanged(file: &DbFile, source: &File) -> Result<()> {
+    let metadata = source
+        .metadata()
+        .with_context(|| "Failed to read metadata")?;
+    match file.changed(&metadata) {
+        true => bail!("Changed since crawled; crawl again before pushing it"),
+        false => Ok(()),
     }
 }
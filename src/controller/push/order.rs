@@ -0,0 +1,179 @@
+use anyhow::{bail, Error, Result};
+use globset::GlobSet;
+use std::cmp::Ordering;
+
+/// The order in which files are pushed.
+#[derive(Debug, PartialEq)]
+pub enum Order {
+    /// as they were crawled, aggregates last
+    Crawl,
+    Smallest,
+    /// most recently modified first
+    Newest,
+    Path,
+}
+
+impl TryFrom<&str> for Order {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "crawl" => Ok(Order::Crawl),
+            "smallest" => Ok(Order::Smallest),
+            "newest" => Ok(Order::Newest),
+            "path" => Ok(Order::Path),
+            s => bail!("Not an order: {}", s),
+        }
+    }
+}
+
+/// Files matching the first of `priorities` are pushed first, then those matching the second one,
+/// and so on; files with the same priority are pushed in `order`.
+pub struct Policy {
+    pub order: Order,
+    pub priorities: GlobSet,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            order: Order::Crawl,
+            priorities: GlobSet::empty(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn is_default(&self) -> bool {
+        self.order == Order::Crawl && self.priorities.is_empty()
+    }
+
+    pub fn key(&self, path: &str, size: u64, mtime: Option<u64>) -> Key {
+        Key {
+            priority: self
+                .priorities
+                .matches(path)
+                .into_iter()
+                .min()
+                .unwrap_or(self.priorities.len()),
+            size,
+            mtime: mtime.unwrap_or_default(),
+            path: path.to_string(),
+        }
+    }
+
+    pub fn sort<T>(&self, items: &mut [(Key, T)]) {
+        items.sort_by(|(a, _), (b, _)| {
+            a.priority.cmp(&b.priority).then_with(|| match self.order {
+                Order::Crawl => Ordering::Equal,
+                Order::Smallest => a.size.cmp(&b.size),
+                Order::Newest => b.mtime.cmp(&a.mtime),
+                Order::Path => a.path.cmp(&b.path),
+            })
+        })
+    }
+}
+
+/// What files are sorted by. An aggregate is sorted as its most important file.
+pub struct Key {
+    priority: usize,
+    size: u64,
+    mtime: u64,
+    path: String,
+}
+
+impl Key {
+    /// The key of an aggregate, from the keys of its files.
+    pub fn merge(self, other: Key) -> Key {
+        Key {
+            priority: self.priority.min(other.priority),
+            size: self.size + other.size,
+            mtime: self.mtime.max(other.mtime),
+            path: self.path.min(other.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use globset::{Glob, GlobSetBuilder};
+
+    fn sorted(policy: &Policy) -> Vec<&'static str> {
+        let mut items = [
+            ("b/large.bin", 300, 1),
+            ("Documents/old.txt", 200, 2),
+            ("a/small.bin", 100, 3),
+            ("Photos/new.jpg", 400, 4),
+        ]
+        .into_iter()
+        .map(|(path, size, mtime)| (policy.key(path, size, Some(mtime)), path))
+        .collect::<Vec<_>>();
+        policy.sort(&mut items);
+        items.into_iter().map(|(_, path)| path).collect()
+    }
+
+    #[test]
+    fn orders() {
+        let policy = |order| Policy {
+            order,
+            priorities: GlobSet::empty(),
+        };
+        assert_eq!(
+            sorted(&policy(Order::Crawl)),
+            [
+                "b/large.bin",
+                "Documents/old.txt",
+                "a/small.bin",
+                "Photos/new.jpg"
+            ]
+        );
+        assert_eq!(
+            sorted(&policy(Order::Smallest)),
+            [
+                "a/small.bin",
+                "Documents/old.txt",
+                "b/large.bin",
+                "Photos/new.jpg"
+            ]
+        );
+        assert_eq!(
+            sorted(&policy(Order::Newest)),
+            [
+                "Photos/new.jpg",
+                "a/small.bin",
+                "Documents/old.txt",
+                "b/large.bin"
+            ]
+        );
+        assert_eq!(
+            sorted(&policy(Order::Path)),
+            [
+                "Documents/old.txt",
+                "Photos/new.jpg",
+                "a/small.bin",
+                "b/large.bin"
+            ]
+        );
+    }
+
+    #[test]
+    fn priorities() {
+        let mut priorities = GlobSetBuilder::new();
+        priorities.add(Glob::new("Documents/**").unwrap());
+        priorities.add(Glob::new("**/*.jpg").unwrap());
+        let policy = Policy {
+            order: Order::Smallest,
+            priorities: priorities.build().unwrap(),
+        };
+        assert_eq!(
+            sorted(&policy),
+            [
+                "Documents/old.txt",
+                "Photos/new.jpg",
+                "a/small.bin",
+                "b/large.bin"
+            ]
+        );
+    }
+}
@@ -0,0 +1,598 @@
+use crate::aggregate::archive::Archive;
+use crate::aggregate::repository::{Aggregate, Repository as AggregatesRepository};
+use crate::chunk::repository::Chunk as DbChunk;
+use crate::chunk::{self, ClearChunk, LocalEncryptedChunk, Metadata, StreamedChunk};
+use crate::chunk_location::repository::{ChunkLocation, Repository as ChunkLocationsRepository};
+use crate::file::repository::{File as DbFile, Repository as FilesRepository};
+use crate::hash::ChunkedSha256;
+use crate::metrics::Metric;
+use crate::store::Store;
+use crate::{ChunksRepository, Pgp};
+use anyhow::{anyhow, Context, Error, Result};
+use std::collections::HashSet;
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// How many chunks each stage of the pipeline processes at once.
+pub struct Parallelism {
+    pub readers: usize,
+    pub encryptors: usize,
+    pub uploaders: usize,
+    pub finalizers: usize,
+    /// chunks waiting between two stages
+    pub queue_size: usize,
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Self {
+            readers: 2,
+            encryptors: 2,
+            uploaders: 2,
+            finalizers: 1,
+            queue_size: 1,
+        }
+    }
+}
+
+/// What the stages share.
+pub struct Shared {
+    pub pgp: Pgp,
+    pub store: Arc<Box<dyn Store>>,
+    pub store_name: String,
+    pub files_repository: Arc<FilesRepository>,
+    pub chunks_repository: Arc<ChunksRepository>,
+    pub chunk_locations_repository: Arc<ChunkLocationsRepository>,
+    pub aggregates_repository: Arc<AggregatesRepository>,
+    pub metrics: mpsc::Sender<Metric>,
+    /// files found failed in this run, so that their attempts are counted once
+    pub failed_files: Arc<Mutex<HashSet<Uuid>>>,
+    pub interrupted: Arc<AtomicBool>,
+    /// chunks pushed in this run
+    pub pushed: AtomicU64,
+    pub progress: Arc<Progress>,
+}
+
+impl Shared {
+    fn fail(&self, task: &Task, error: &Error) {
+        log::error!(
+            "Failed to process chunk {} of {}: {:#}",
+            task.idx,
+            task.file_path,
+            error
+        );
+        let error = format!("chunk {}: {:#}", task.idx + 1, error);
+        let first = self.failed_files.lock().unwrap().insert(task.file_uuid);
+        if let Err(e) = self
+            .chunks_repository
+            .mark_failed(&task.chunk_uuid, &error)
+            .and_then(|_| match first {
+                true => self.files_repository.mark_failed(&task.file_uuid, &error),
+                false => Ok(()),
+            })
+        {
+            log::warn!("Failed to record failure of {}: {:#}", task.file_path, e);
+        }
+    }
+}
+
+/// The bytes of the chunks queued in this run: the uploaded ones, and the ones reserved by chunks
+/// not through the upload stage yet, which may turn out to be stored already or fail.
+#[derive(Default)]
+pub struct Progress {
+    /// uploaded and reserved bytes
+    bytes: Mutex<(u64, u64)>,
+    released: Condvar,
+}
+
+impl Progress {
+    /// The uploaded and reserved bytes.
+    pub fn bytes(&self) -> (u64, u64) {
+        *self.bytes.lock().unwrap()
+    }
+
+    /// Waits until some of the `reserved` bytes are released.
+    pub fn wait(&self, reserved: u64) {
+        drop(
+            self.released
+                .wait_while(self.bytes.lock().unwrap(), |(_, r)| *r == reserved)
+                .unwrap(),
+        );
+    }
+
+    fn reserve(self: &Arc<Self>, bytes: u64) -> Reservation {
+        self.bytes.lock().unwrap().1 += bytes;
+        Reservation {
+            progress: self.clone(),
+            bytes,
+            uploaded: false,
+        }
+    }
+}
+
+/// The bytes a chunk reserves until it is uploaded, or dropped.
+struct Reservation {
+    progress: Arc<Progress>,
+    bytes: u64,
+    uploaded: bool,
+}
+
+impl Reservation {
+    fn release(mut self, uploaded: bool) {
+        self.uploaded = uploaded;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut bytes = self.progress.bytes.lock().unwrap();
+        bytes.1 -= self.bytes;
+        if self.uploaded {
+            bytes.0 += self.bytes;
+        }
+        self.progress.released.notify_all();
+    }
+}
+
+/// A chunk going through the pipeline.
+pub struct Task {
+    file_uuid: Uuid,
+    file_path: String,
+    /// chunks of the file
+    chunks: u64,
+    chunk_uuid: Uuid,
+    idx: u64,
+    payload_size: u64,
+    hash: Arc<Mutex<ChunkedSha256>>,
+    /// where each file is in the archive, for an aggregate
+    layout: Vec<Aggregate>,
+    /// the share of the memory limit the chunk uses until it is finalized
+    memory: Option<OwnedSemaphorePermit>,
+    /// the bytes counted against the quota until the chunk is uploaded
+    reservation: Option<Reservation>,
+}
+
+impl Task {
+    pub fn new(file: &DbFile, chunk: &DbChunk, hash: Arc<Mutex<ChunkedSha256>>) -> Self {
+        Self {
+            file_uuid: file.uuid,
+            file_path: file.path.clone(),
+            chunks: file.chunks,
+            chunk_uuid: chunk.uuid,
+            idx: chunk.idx,
+            payload_size: chunk.payload_size,
+            hash,
+            layout: Vec::new(),
+            memory: None,
+            reservation: None,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::new(self.file_path.clone(), self.idx, self.chunks)
+    }
+}
+
+/// Records where each file is in the archive, once it is pushed.
+fn record_layout(layout: &[Aggregate], aggregates_repository: &AggregatesRepository) -> Result<()> {
+    for aggregate in layout {
+        aggregates_repository
+            .update_layout(aggregate)
+            .with_context(|| format!("Failed to record {} in archive", aggregate.file_path))?;
+    }
+    Ok(())
+}
+
+pub enum Source {
+    /// a part of a file, which must not change until it is read
+    File {
+        path: PathBuf,
+        file: DbFile,
+        offset: u64,
+    },
+    /// an archive, read as it is encrypted
+    Archive(Archive),
+}
+
+enum Payload {
+    Bytes(Vec<u8>),
+    Archive(Archive),
+}
+
+impl Source {
+    /// The memory, in KiB, used by the chunk in the pipeline: a file's chunk is kept in clear
+    /// text next to its encrypted data, an archive is encrypted as it is read.
+    fn memory(&self, payload_size: u64) -> u64 {
+        let bytes = match self {
+            Source::File { .. } => 2 * payload_size,
+            Source::Archive(_) => payload_size,
+        };
+        bytes.div_ceil(1024)
+    }
+
+    fn read(self, size: u64) -> Result<Payload> {
+        match self {
+            Source::File { path, file, offset } => {
+                let mut source = File::open(&path).with_context(|| "Failed to open")?;
+                super::unchanged(&file, &source)?;
+                source
+                    .seek(SeekFrom::Start(offset))
+                    .with_context(|| "Failed to seek")?;
+
+                let mut data = vec![0; size as usize];
+                source
+                    .read_exact(&mut data)
+                    .with_context(|| "Failed to read")?;
+                super::unchanged(&file, &source)?;
+                Ok(Payload::Bytes(data))
+            }
+            Source::Archive(archive) => Ok(Payload::Archive(archive)),
+        }
+    }
+}
+
+impl Payload {
+    /// The chunk as already in the store, at `location`.
+    fn stored(
+        self,
+        uuid: Uuid,
+        metadata: Metadata,
+        location: ChunkLocation,
+    ) -> Result<LocalEncryptedChunk> {
+        match self {
+            Payload::Bytes(data) => Ok(ClearChunk::new(uuid, metadata, data).stored(location)),
+            Payload::Archive(archive) => {
+                StreamedChunk::new(uuid, metadata, archive.size(), archive.into_reader())
+                    .stored(location)
+            }
+        }
+    }
+
+    fn encrypt(self, uuid: Uuid, metadata: Metadata, pgp: &Pgp) -> Result<LocalEncryptedChunk> {
+        match self {
+            Payload::Bytes(data) => ClearChunk::new(uuid, metadata, data).encrypt(pgp),
+            Payload::Archive(archive) => {
+                StreamedChunk::new(uuid, metadata, archive.size(), archive.into_reader())
+                    .encrypt(pgp)
+            }
+        }
+    }
+}
+
+/// Reads, encrypts, uploads and finalizes chunks, each stage with its own parallelism so that
+/// the CPU works while uploads wait, and the other way around.
+pub struct Pipeline {
+    input: Sender<(Task, Source)>,
+    done: JoinHandle<()>,
+    /// KiB of memory left for chunks entering the pipeline, if limited
+    memory: Option<(Arc<Semaphore>, u32)>,
+    progress: Arc<Progress>,
+    runtime: Handle,
+}
+
+impl Pipeline {
+    pub fn start(
+        shared: Arc<Shared>,
+        parallelism: &Parallelism,
+        memory_limit: Option<u64>,
+        runtime: &Runtime,
+    ) -> Self {
+        let _guard = runtime.enter();
+        let progress = shared.progress.clone();
+        let queue_size = parallelism.queue_size.max(1);
+        let (input, to_read) = channel::<(Task, Source)>(queue_size);
+        let (read, to_encrypt) = channel(queue_size);
+        let (encrypted, to_upload) = channel(queue_size);
+        let (uploaded, to_finalize) = channel(queue_size);
+
+        let s = shared.clone();
+        stage(
+            to_read,
+            Some(read),
+            parallelism.readers,
+            move |(task, source)| {
+                let shared = s.clone();
+                async move {
+                    // queued chunks are left for the next push
+                    if shared.interrupted.load(Ordering::SeqCst) {
+                        log::debug!("skip chunk {} of {}: interrupted", task.idx, task.file_path);
+                        return None;
+                    }
+                    let size = task.payload_size;
+                    match blocking(move || source.read(size)).await {
+                        Ok(payload) => Some((task, payload)),
+                        Err(e) => {
+                            shared.fail(&task, &e);
+                            None
+                        }
+                    }
+                }
+            },
+        );
+
+        let s = shared.clone();
+        stage(
+            to_encrypt,
+            Some(encrypted),
+            parallelism.encryptors,
+            move |(task, payload): (Task, Payload)| {
+                let shared = s.clone();
+                async move {
+                    // an interrupted push may have uploaded it already
+                    let stored = match chunk::find_stored(
+                        task.chunk_uuid,
+                        shared.store.as_ref().as_ref(),
+                        &shared.store_name,
+                        shared.chunk_locations_repository.clone(),
+                    )
+                    .await
+                    {
+                        Ok(stored) => stored,
+                        Err(e) => {
+                            shared.fail(&task, &e);
+                            return None;
+                        }
+                    };
+                    let s = shared.clone();
+                    let uuid = task.chunk_uuid;
+                    let metadata = task.metadata();
+                    match blocking(move || match stored {
+                        Some(location) => payload.stored(uuid, metadata, location),
+                        None => {
+                            log::debug!("encrypt chunk {} of {}", metadata.idx(), metadata.file());
+                            payload.encrypt(uuid, metadata, &s.pgp)
+                        }
+                    })
+                    .await
+                    {
+                        Ok(chunk) => Some((task, chunk)),
+                        Err(e) => {
+                            shared.fail(&task, &e);
+                            None
+                        }
+                    }
+                }
+            },
+        );
+
+        let s = shared.clone();
+        stage(
+            to_upload,
+            Some(uploaded),
+            parallelism.uploaders,
+            move |(mut task, chunk): (Task, LocalEncryptedChunk)| {
+                let shared = s.clone();
+                async move {
+                    log::debug!("upload chunk {} of {}", task.idx, task.file_path);
+                    let upload = !chunk.is_pushed();
+                    match chunk
+                        .push(
+                            shared.store.as_ref().as_ref(),
+                            &shared.store_name,
+                            shared.chunk_locations_repository.clone(),
+                        )
+                        .await
+                    {
+                        Ok(chunk) => {
+                            if let Some(reservation) = task.reservation.take() {
+                                reservation.release(upload);
+                            }
+                            Some((task, chunk))
+                        }
+                        Err(e) => {
+                            shared.fail(&task, &e);
+                            None
+                        }
+                    }
+                }
+            },
+        );
+
+        let s = shared;
+        let done = stage(
+            to_finalize,
+            None::<Sender<()>>,
+            parallelism.finalizers,
+            move |(mut task, chunk): (Task, LocalEncryptedChunk)| {
+                let shared = s.clone();
+                async move {
+                    let bytes = task.payload_size;
+                    let s = shared.clone();
+                    let hash = task.hash.clone();
+                    let layout = std::mem::take(&mut task.layout);
+                    match blocking(move || {
+                        record_layout(&layout, &s.aggregates_repository)?;
+                        chunk.finalize(
+                            s.files_repository.clone(),
+                            s.chunks_repository.clone(),
+                            s.chunk_locations_repository.clone(),
+                            hash,
+                            &s.metrics,
+                        )
+                    })
+                    .await
+                    {
+                        Ok(_) => {
+                            shared.pushed.fetch_add(1, Ordering::SeqCst);
+                            let _ = shared.metrics.send(Metric::ChunkProcessed);
+                            let _ = shared.metrics.send(Metric::BytesTransferred(bytes));
+                        }
+                        Err(e) => shared.fail(&task, &e),
+                    }
+                    None
+                }
+            },
+        );
+
+        let memory = memory_limit.map(|bytes| {
+            let kib = (bytes / 1024).clamp(1, u32::MAX as u64);
+            (Arc::new(Semaphore::new(kib as usize)), kib as u32)
+        });
+
+        Self {
+            input,
+            done,
+            memory,
+            progress,
+            runtime: runtime.handle().clone(),
+        }
+    }
+
+    /// Queues a chunk, waiting while the pipeline is full or, if limited, until there is enough
+    /// memory left for it. A chunk larger than the limit waits for the pipeline to be empty.
+    pub fn queue(&self, mut task: Task, source: Source) -> Result<()> {
+        if let Source::Archive(archive) = &source {
+            task.layout = archive
+                .entries()
+                .iter()
+                .map(|entry| {
+                    log::debug!(
+                        "Archive: {} at {} ({} bytes)",
+                        entry.file_path,
+                        entry.offset,
+                        entry.length
+                    );
+                    Aggregate {
+                        aggregate_path: task.file_path.clone(),
+                        file_path: entry.file_path.clone(),
+                        offset: Some(entry.offset),
+                        length: Some(entry.length),
+                    }
+                })
+                .collect();
+        }
+        if let Some((semaphore, limit)) = &self.memory {
+            let kib = source.memory(task.payload_size).min(*limit as u64) as u32;
+            task.memory = Some(
+                self.runtime
+                    .block_on(semaphore.clone().acquire_many_owned(kib))
+                    .map_err(|_| anyhow!("Pipeline stopped"))?,
+            );
+        }
+        task.reservation = Some(self.progress.reserve(task.payload_size));
+        self.input
+            .blocking_send((task, source))
+            .map_err(|_| anyhow!("Pipeline stopped"))
+    }
+
+    /// Waits until all queued chunks went through the pipeline.
+    pub fn finish(self, runtime: &Runtime) {
+        drop(self.input);
+        if let Err(e) = runtime.block_on(self.done) {
+            log::error!("Pipeline failed: {:#}", e);
+        }
+    }
+}
+
+/// Processes the items received, at most `parallelism` at once, and sends the results to the
+/// next stage. Completes once all items are processed.
+fn stage<I, O, F, Fut>(
+    mut input: Receiver<I>,
+    output: Option<Sender<O>>,
+    parallelism: usize,
+    work: F,
+) -> JoinHandle<()>
+where
+    I: Send + 'static,
+    O: Send + 'static,
+    F: Fn(I) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<O>> + Send + 'static,
+{
+    let parallelism = parallelism.max(1);
+    tokio::spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(parallelism));
+        while let Some(item) = input.recv().await {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let output = output.clone();
+            let work = work(item);
+            tokio::spawn(async move {
+                if let (Some(result), Some(output)) = (work.await, output) {
+                    let _ = output.send(result).await;
+                }
+                // released once the next stage took the result, so that results do not pile up
+                drop(permit);
+            });
+        }
+        let _ = semaphore.acquire_many(parallelism as u32).await;
+    })
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| anyhow!("Failed to run task: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stages() {
+        let (input, to_first) = channel::<usize>(1);
+        let (first, to_second) = channel(1);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::new(AtomicUsize::new(0));
+        // holds the second stage until opened
+        let gate = Arc::new(Semaphore::new(0));
+
+        let (r, m, p) = (running.clone(), max_running.clone(), processed.clone());
+        stage(to_first, Some(first), 3, move |i| {
+            let (running, max_running, processed) = (r.clone(), m.clone(), p.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                processed.fetch_add(1, Ordering::SeqCst);
+                Some(i)
+            }
+        });
+        let (g, d) = (gate.clone(), drained.clone());
+        let done = stage(to_second, None::<Sender<()>>, 1, move |_| {
+            let (gate, drained) = (g.clone(), d.clone());
+            async move {
+                gate.acquire().await.unwrap().forget();
+                drained.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        });
+        let feed = tokio::spawn(async move {
+            for i in 0..20 {
+                input.send(i).await.unwrap();
+            }
+        });
+
+        // the first stage waits for the second one: 3 results waiting to be sent, 1 in the
+        // channel, 1 processed by the second stage and 1 waiting for it
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        assert_eq!(processed.load(Ordering::SeqCst), 6);
+        assert_eq!(drained.load(Ordering::SeqCst), 0);
+
+        // all items go through once the input is closed
+        gate.add_permits(20);
+        feed.await.unwrap();
+        done.await.unwrap();
+        assert_eq!(processed.load(Ordering::SeqCst), 20);
+        assert_eq!(drained.load(Ordering::SeqCst), 20);
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+    }
+}
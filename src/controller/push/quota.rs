@@ -0,0 +1,99 @@
+use anyhow::{anyhow, bail, Result};
+use byte_unit::Byte;
+use std::time::{Duration, Instant};
+
+/// Limits of a single push; once one is reached, no more chunks are queued.
+#[derive(Default)]
+pub struct Quota {
+    /// clear text bytes to upload; the chunks already in the store do not count, and the first
+    /// chunk is pushed even if larger, so that each push makes progress
+    pub bytes: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+impl Quota {
+    /// Why the next chunk cannot be pushed, `pushed` bytes being uploaded since `start`, if so.
+    pub fn exceeded(&self, start: Instant, pushed: u64, next: u64) -> Option<String> {
+        if let Some(bytes) = self
+            .bytes
+            .filter(|bytes| pushed > 0 && pushed + next > *bytes)
+        {
+            return Some(format!(
+                "Max bytes ({}) reached",
+                Byte::from_bytes(bytes as u128).get_appropriate_unit(false)
+            ));
+        }
+        if let Some(duration) = self
+            .duration
+            .filter(|duration| start.elapsed() >= *duration)
+        {
+            return Some(format!("Max duration ({}s) reached", duration.as_secs()));
+        }
+        None
+    }
+}
+
+/// Parses durations such as `90s`, `45m`, `6h`, `2d` or `1h30m`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let count = number
+            .parse::<u64>()
+            .map_err(|_| anyhow!("Not a duration: {}", value))?;
+        seconds += count
+            * match c {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                _ => bail!("Not a duration: {}", value),
+            };
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        bail!("Not a duration: {}", value);
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("6h").unwrap(), Duration::from_secs(6 * 3600));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(
+            parse_duration("2d").unwrap(),
+            Duration::from_secs(2 * 86400)
+        );
+        assert!(parse_duration("6").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("6w").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn exceeded() {
+        let quota = Quota {
+            bytes: Some(1000),
+            duration: None,
+        };
+        assert!(quota.exceeded(Instant::now(), 600, 400).is_none());
+        assert!(quota.exceeded(Instant::now(), 600, 401).is_some());
+        assert!(quota.exceeded(Instant::now(), 0, 2000).is_none());
+
+        let quota = Quota {
+            bytes: None,
+            duration: Some(Duration::from_secs(0)),
+        };
+        assert!(quota.exceeded(Instant::now(), 0, 0).is_some());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::aggregate::repository::Repository as AggregatesRepository;
+use crate::chunk::repository::{Chunk as DbChunk, Repository as ChunksRepository};
+use crate::chunk::{Chunk, EncryptedChunk, RemoteEncryptedChunk};
+use crate::file::repository::{File as DbFile, Repository as FilesRepository};
+use crate::file::Mode;
+use crate::pending_restore::repository::Repository as PendingRestoresRepository;
+use crate::status::Status;
+use crate::store::{RestorePending, Store};
+use crate::{Pgp, PooledSqliteConnectionManager};
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::Archive;
+use tokio::runtime::Runtime;
+
+pub struct Config<'a> {
+    pub target_folder: &'a str,
+}
+
+pub fn execute(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    pgp: Pgp,
+    store: Box<dyn Store>,
+    runtime: Runtime,
+) -> Result<()> {
+    Restore {
+        target_folder: config.target_folder,
+        files_repository: FilesRepository::new(sqlite.clone()),
+        chunks_repository: ChunksRepository::new(sqlite.clone()),
+        aggregates_repository: AggregatesRepository::new(sqlite.clone()),
+        pending_restores_repository: PendingRestoresRepository::new(sqlite),
+        pgp,
+        store,
+        runtime,
+    }
+    .execute()
+}
+
+struct Restore<'a> {
+    target_folder: &'a str,
+    files_repository: FilesRepository,
+    chunks_repository: ChunksRepository,
+    aggregates_repository: AggregatesRepository,
+    pending_restores_repository: PendingRestoresRepository,
+    pgp: Pgp,
+    store: Box<dyn Store>,
+    runtime: Runtime,
+}
+
+impl<'a> Restore<'a> {
+    fn execute(&self) -> Result<()> {
+        log::info!("Restoring files to `{}`...", self.target_folder);
+
+        for file in self
+            .files_repository
+            .find_by_status_and_mode(Status::Done, Mode::Chunked)
+            .with_context(|| "Failed to load chunked files")?
+        {
+            if let Err(e) = self.restore_chunked_file(&file) {
+                log::error!("Failed to restore chunked file {}: {:#}", file.path, e);
+            }
+        }
+
+        for aggregate in self
+            .files_repository
+            .find_by_status_and_mode(Status::Done, Mode::Aggregate)
+            .with_context(|| "Failed to load aggregate files")?
+        {
+            if let Err(e) = self.restore_aggregate(&aggregate) {
+                log::error!(
+                    "Failed to restore aggregate file {}: {:#}",
+                    aggregate.path,
+                    e
+                );
+            }
+        }
+
+        match self.pending_restores_repository.count() {
+            Ok(0) => {}
+            Ok(count) => log::warn!(
+                "{} chunks are being restored from archive; run restore again once they are available",
+                count
+            ),
+            Err(e) => log::warn!("Failed to fetch pending restores count: {:#}", e),
+        }
+
+        Ok(())
+    }
+
+    fn restore_chunked_file(&self, file: &DbFile) -> Result<()> {
+        let path = self.absolute_path(&file.path);
+        if Self::is_restored(&path, file) {
+            log::debug!("{}: already restored; skipping", file.path);
+            return Ok(());
+        }
+
+        let chunks = self
+            .chunks_repository
+            .find_by_file_uuid(&file.uuid)
+            .with_context(|| "Failed to load chunks")?;
+
+        let tmp_path = Self::tmp_path(&path);
+        fs::create_dir_all(path.parent().expect("file has a parent"))
+            .with_context(|| "Failed to create parent folder")?;
+        let mut target = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+
+        // we keep fetching the chunks after an archived one to request all restores at once
+        let mut complete = true;
+        for chunk in chunks {
+            match self.fetch(&chunk).with_context(|| {
+                format!("Failed to restore chunk {}/{}", chunk.idx + 1, file.chunks)
+            })? {
+                Some(payload) if complete => target
+                    .write_all(&payload)
+                    .with_context(|| format!("Failed to write {}", tmp_path.display()))?,
+                Some(_) => {}
+                None => complete = false,
+            }
+        }
+
+        if !complete {
+            drop(target);
+            let _ = fs::remove_file(&tmp_path);
+            return Ok(());
+        }
+
+        target
+            .sync_all()
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to move file to {}", path.display()))?;
+        log::info!("{} restored", file.path);
+        Ok(())
+    }
+
+    fn restore_aggregate(&self, aggregate: &DbFile) -> Result<()> {
+        let mut missing = HashSet::new();
+        for member in self
+            .aggregates_repository
+            .find_by_aggregate_path(&aggregate.path)
+            .with_context(|| "Failed to load aggregated files")?
+        {
+            let file = self
+                .files_repository
+                .find_by_path(&member.file_path)
+                .with_context(|| format!("Failed to load {}", member.file_path))?
+                .ok_or_else(|| anyhow!("File {} not found in database", member.file_path))?;
+            if Self::is_restored(&self.absolute_path(&file.path), &file) {
+                log::debug!("{}: already restored; skipping", file.path);
+            } else {
+                missing.insert(file.path);
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = self
+            .chunks_repository
+            .find_by_file_uuid_and_index(&aggregate.uuid, 0)
+            .with_context(|| "Failed to find chunk 1/1")?
+            .ok_or_else(|| anyhow!("Failed to find chunk 1/1"))?;
+
+        let payload = match self
+            .fetch(&chunk)
+            .with_context(|| "Failed to restore chunk 1/1")?
+        {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+
+        let mut archive = Archive::new(Cursor::new(payload));
+        for entry in archive
+            .entries()
+            .with_context(|| "Failed to read archive")?
+        {
+            let mut entry = entry.with_context(|| "Failed to read archive")?;
+            let file_path = entry
+                .path()
+                .with_context(|| "Failed to read archive")?
+                .to_str()
+                .map(String::from)
+                .unwrap_or_default();
+
+            if missing.remove(&file_path) {
+                self.write_file(&file_path, &mut entry)
+                    .with_context(|| format!("Failed to restore {}", file_path))?;
+                log::info!("{} restored", file_path);
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!("{} files not found in archive", missing.len());
+        }
+
+        Ok(())
+    }
+
+    /// Downloads and decrypts a chunk. Returns `None` if the chunk is archived and being restored.
+    fn fetch(&self, chunk: &DbChunk) -> Result<Option<Vec<u8>>> {
+        let bytes = match self.runtime.block_on(self.store.get(chunk.uuid)) {
+            Ok(bytes) => bytes,
+            Err(e) if RestorePending::is(&e) => {
+                log::info!("{:#}", e);
+                self.pending_restores_repository
+                    .insert(&chunk.uuid)
+                    .with_context(|| "Failed to save pending restore")?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e).with_context(|| "Failed to download"),
+        };
+
+        self.pending_restores_repository
+            .delete(&chunk.uuid)
+            .with_context(|| "Failed to delete pending restore")?;
+
+        let clear_chunk = RemoteEncryptedChunk::from(bytes)
+            .decrypt(&self.pgp)
+            .with_context(|| "Failed to decrypt")?;
+
+        if !chunk.sha256.is_empty() && clear_chunk.sha256() != chunk.sha256 {
+            bail!("Checksum mismatch");
+        }
+
+        Ok(Some(clear_chunk.payload().into()))
+    }
+
+    fn write_file<R>(&self, path: &str, reader: &mut R) -> Result<()>
+    where
+        R: Read,
+    {
+        let path = self.absolute_path(path);
+        let tmp_path = Self::tmp_path(&path);
+
+        fs::create_dir_all(path.parent().expect("file has a parent"))?;
+        let mut target = File::create(&tmp_path)?;
+        io::copy(reader, &mut target)?;
+        target.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn absolute_path(&self, path: &str) -> PathBuf {
+        let mut path_buf = PathBuf::from(self.target_folder);
+        path_buf.push(path);
+        path_buf
+    }
+
+    fn tmp_path(path: &Path) -> PathBuf {
+        path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name()
+                .expect("file has a name")
+                .to_str()
+                .unwrap_or_default()
+        ))
+    }
+
+    /// Whether the file exists in the target folder with the expected size and sha256.
+    fn is_restored(path: &Path, file: &DbFile) -> bool {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() == file.size => {}
+            _ => return false,
+        }
+
+        if file.sha256.is_empty() {
+            return true;
+        }
+
+        let mut hasher = Sha256::new();
+        match File::open(path).and_then(|mut f| io::copy(&mut f, &mut hasher)) {
+            Ok(_) => format!("{:x}", hasher.finalize()) == file.sha256,
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::chunk::repository::{Chunk as DbChunk, Repository as ChunksRepository};
+use crate::chunk_location::repository::{ChunkLocation, Repository as ChunkLocationsRepository};
+use crate::controller::transfer;
+use crate::status::Status;
+use crate::store::retention::Retained;
+use crate::store::Store;
+use crate::PooledSqliteConnectionManager;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
+
+/// Moves the chunks of files unchanged for `unchanged_for` from store `from` to store `to`.
+pub struct Rule<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub unchanged_for: Duration,
+}
+
+pub struct Config<'a> {
+    pub rules: Vec<Rule<'a>>,
+}
+
+pub fn execute(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    stores: HashMap<String, Box<dyn Store>>,
+    runtime: Runtime,
+) -> Result<()> {
+    Tier {
+        rules: config.rules,
+        chunks_repository: ChunksRepository::new(sqlite.clone()),
+        chunk_locations_repository: ChunkLocationsRepository::new(sqlite),
+        stores,
+        runtime,
+    }
+    .execute()
+}
+
+struct Tier<'a> {
+    rules: Vec<Rule<'a>>,
+    chunks_repository: ChunksRepository,
+    chunk_locations_repository: ChunkLocationsRepository,
+    stores: HashMap<String, Box<dyn Store>>,
+    runtime: Runtime,
+}
+
+impl<'a> Tier<'a> {
+    fn execute(&self) -> Result<()> {
+        if self.rules.is_empty() {
+            log::info!("No tier rules configured");
+        }
+
+        for rule in &self.rules {
+            log::info!(
+                "Moving chunks unchanged for {} days from `{}` to `{}`...",
+                rule.unchanged_for.as_secs() / 86400,
+                rule.from,
+                rule.to
+            );
+            if let Err(e) = self.apply(rule) {
+                log::error!(
+                    "Failed to move chunks from `{}` to `{}`: {:#}",
+                    rule.from,
+                    rule.to,
+                    e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn store(&self, name: &str) -> Result<&dyn Store> {
+        self.stores
+            .get(name)
+            .map(|store| store.as_ref())
+            .ok_or_else(|| anyhow!("Store `{}` is not configured", name))
+    }
+
+    fn apply(&self, rule: &Rule) -> Result<()> {
+        let from = self.store(rule.from)?;
+        let to = self.store(rule.to)?;
+
+        let before = SystemTime::now()
+            .checked_sub(rule.unchanged_for)
+            .and_then(|before| before.duration_since(UNIX_EPOCH).ok())
+            .map(|before| before.as_secs())
+            .unwrap_or_default();
+
+        let chunks = self
+            .chunks_repository
+            .find_by_store_unchanged_before(rule.from, before)
+            .with_context(|| "Failed to load chunks")?;
+
+        let total = chunks.len();
+        let mut retained = 0;
+        let mut failed = 0;
+        for chunk in chunks {
+            match self.move_chunk(rule, from, to, &chunk) {
+                Ok(true) => {}
+                Ok(false) => retained += 1,
+                Err(e) => {
+                    log::error!("Failed to move chunk {}: {:#}", chunk.uuid, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        log::info!(
+            "{} chunks moved, {} still retained, {} failed",
+            total - retained - failed,
+            retained,
+            failed
+        );
+        Ok(())
+    }
+
+    /// Copies the chunk to the cold store, then deletes it from the hot one. A chunk already
+    /// copied by an interrupted run is only deleted. Returns `false` when the chunk is still under
+    /// retention in the hot store, and thus left untouched.
+    fn move_chunk(
+        &self,
+        rule: &Rule,
+        from: &dyn Store,
+        to: &dyn Store,
+        chunk: &DbChunk,
+    ) -> Result<bool> {
+        let locations = self
+            .chunk_locations_repository
+            .find_by_chunk_uuid(&chunk.uuid)
+            .with_context(|| "Failed to load chunk locations")?;
+        let location = |store: &str| {
+            locations
+                .iter()
+                .find(|location| location.store == store && location.status == Status::Done)
+        };
+
+        if let Some(until) = location(rule.from)
+            .filter(|location| location.is_retained())
+            .and_then(|location| location.retain_until)
+        {
+            log::debug!(
+                "{}: retained in `{}` until {}",
+                chunk.uuid,
+                rule.from,
+                until
+            );
+            return Ok(false);
+        }
+
+        if location(rule.to).is_none() {
+            let (size, sha256) = location(rule.from)
+                .map(|location| (location.size, location.sha256.as_deref()))
+                .unwrap_or((chunk.size, None));
+            let (hash, stored) = transfer::copy(&self.runtime, from, to, chunk.uuid, size, sha256)?;
+
+            self.chunk_locations_repository
+                .insert(&ChunkLocation {
+                    chunk_uuid: chunk.uuid,
+                    store: rule.to.to_string(),
+                    status: Status::Done,
+                    size,
+                    etag: stored.etag,
+                    retain_until: stored.retain_until,
+                    sha256: Some(hash),
+                })
+                .with_context(|| "Failed to record chunk location")?;
+        }
+
+        match self.runtime.block_on(from.delete(chunk.uuid)) {
+            Err(e) if Retained::is(&e) => {
+                log::debug!("{}: {}", chunk.uuid, e);
+                return Ok(false);
+            }
+            result => {
+                result.with_context(|| format!("Failed to delete from `{}`", rule.from))?;
+            }
+        }
+        self.chunk_locations_repository
+            .update_status(&chunk.uuid, rule.from, Status::Moved)
+            .with_context(|| "Failed to update chunk location")?;
+
+        log::debug!("{}: moved to `{}`", chunk.uuid, rule.to);
+        Ok(true)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::chunk::repository::{Chunk as DbChunk, Repository as ChunksRepository};
+use crate::chunk_location::repository::{ChunkLocation, Repository as ChunkLocationsRepository};
+use crate::chunk_transfer::repository::{ChunkTransfer, Repository as ChunkTransfersRepository};
+use crate::status::Status;
+use crate::store::{Store, Stored};
+use crate::PooledSqliteConnectionManager;
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+pub struct Config<'a> {
+    /// identifies the destination store in the progress table
+    pub destination: &'a str,
+    /// name of the source store, as recorded in the chunk locations
+    pub source_store: &'a str,
+    /// name under which the chunk locations in the destination store are recorded
+    pub destination_store: &'a str,
+}
+
+pub fn execute(
+    config: Config,
+    sqlite: PooledSqliteConnectionManager,
+    from: Box<dyn Store>,
+    to: Box<dyn Store>,
+    runtime: Runtime,
+) -> Result<()> {
+    Transfer {
+        destination: config.destination,
+        source_store: config.source_store,
+        destination_store: config.destination_store,
+        chunks_repository: ChunksRepository::new(sqlite.clone()),
+        chunk_locations_repository: ChunkLocationsRepository::new(sqlite.clone()),
+        chunk_transfers_repository: ChunkTransfersRepository::new(sqlite),
+        from,
+        to,
+        runtime,
+    }
+    .execute()
+}
+
+struct Transfer<'a> {
+    destination: &'a str,
+    source_store: &'a str,
+    destination_store: &'a str,
+    chunks_repository: ChunksRepository,
+    chunk_locations_repository: ChunkLocationsRepository,
+    chunk_transfers_repository: ChunkTransfersRepository,
+    from: Box<dyn Store>,
+    to: Box<dyn Store>,
+    runtime: Runtime,
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Copies the object as-is, checking it has the expected `size` and `sha256` sum, the latter when
+/// known, and checks that the destination holds it afterwards; the destination is not read back,
+/// as archived objects cannot be read right away. Returns the object's sha-256 sum and what the
+/// destination reports.
+pub fn copy(
+    runtime: &Runtime,
+    from: &dyn Store,
+    to: &dyn Store,
+    object_id: Uuid,
+    size: u64,
+    sha256: Option<&str>,
+) -> Result<(String, Stored)> {
+    let data = runtime
+        .block_on(from.get(object_id))
+        .with_context(|| "Failed to download")?;
+    if data.len() as u64 != size {
+        bail!(
+            "Unexpected size in source: got {} bytes instead of {} bytes",
+            data.len(),
+            size
+        );
+    }
+    let hash = self::sha256(&data);
+    if sha256.is_some_and(|sha256| sha256 != hash) {
+        bail!("Hash mismatch in source");
+    }
+
+    let stored = runtime
+        .block_on(to.put(object_id, &data))
+        .with_context(|| "Failed to upload")?;
+
+    let head = runtime
+        .block_on(to.head(object_id))
+        .with_context(|| "Failed to verify")?
+        .ok_or_else(|| anyhow!("Not found in destination"))?;
+    if head.size != size {
+        bail!(
+            "Unexpected size in destination: got {} bytes instead of {} bytes",
+            head.size,
+            size
+        );
+    }
+    if head.sha256.is_some_and(|sha256| sha256 != hash) {
+        bail!("Hash mismatch in destination");
+    }
+
+    Ok((hash, stored))
+}
+
+impl<'a> Transfer<'a> {
+    fn execute(&self) -> Result<()> {
+        log::info!("Transferring chunks to `{}`...", self.destination);
+
+        let transferred = self
+            .chunk_transfers_repository
+            .find_by_destination(self.destination)
+            .with_context(|| "Failed to load transferred chunks")?
+            .into_iter()
+            .map(|transfer| transfer.chunk_uuid)
+            .collect::<HashSet<_>>();
+
+        let chunks = self
+            .chunks_repository
+            .find_by_status(Status::Done)
+            .with_context(|| "Failed to load chunks")?;
+
+        let total = chunks.len();
+        let mut skipped = 0;
+        let mut failed = 0;
+        for chunk in chunks {
+            if transferred.contains(&chunk.uuid) {
+                log::debug!("{}: already transferred", chunk.uuid);
+                skipped += 1;
+                continue;
+            }
+
+            if let Err(e) = self.transfer(&chunk) {
+                log::error!("Failed to transfer chunk {}: {:#}", chunk.uuid, e);
+                failed += 1;
+            }
+        }
+
+        log::info!(
+            "{} chunks transferred, {} already transferred, {} failed",
+            total - skipped - failed,
+            skipped,
+            failed
+        );
+
+        Ok(())
+    }
+
+    fn transfer(&self, chunk: &DbChunk) -> Result<()> {
+        // each store may hold a different encryption of the chunk
+        let (size, sha256) = match self
+            .chunk_locations_repository
+            .find_by_chunk_uuid_and_store(&chunk.uuid, self.source_store)
+            .with_context(|| "Failed to load chunk location")?
+        {
+            Some(location) => (location.size, location.sha256),
+            None => (chunk.size, None),
+        };
+
+        let (hash, stored) = copy(
+            &self.runtime,
+            self.from.as_ref(),
+            self.to.as_ref(),
+            chunk.uuid,
+            size,
+            sha256.as_deref(),
+        )?;
+
+        self.chunk_locations_repository
+            .insert(&ChunkLocation {
+                chunk_uuid: chunk.uuid,
+                store: self.destination_store.to_string(),
+                status: Status::Done,
+                size,
+                etag: stored.etag,
+                retain_until: stored.retain_until,
+                sha256: Some(hash.clone()),
+            })
+            .with_context(|| "Failed to record chunk location")?;
+
+        self.chunk_transfers_repository
+            .insert(&ChunkTransfer {
+                destination: self.destination.to_string(),
+                chunk_uuid: chunk.uuid,
+                sha256: hash,
+            })
+            .with_context(|| "Failed to record transfer")?;
+
+        log::debug!("{}: transferred", chunk.uuid);
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@ mod embedded;
 
 pub type PooledSqliteConnectionManager = Pool<SqliteConnectionManager>;
 
-fn open(path: &str) -> Result<Pool<SqliteConnectionManager>> {
+pub fn open(path: &str) -> Result<Pool<SqliteConnectionManager>> {
     let manager = SqliteConnectionManager::file(path);
     let pool = Pool::new(manager)?;
 
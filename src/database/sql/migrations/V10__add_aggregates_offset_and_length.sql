@@ -0,0 +1,2 @@
+alter table aggregates add column offset number; -- offset of the file's data in the aggregate's tar
+alter table aggregates add column length number; -- length of the file's data in the aggregate's tar
\ No newline at end of file
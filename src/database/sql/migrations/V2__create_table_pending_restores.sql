@@ -0,0 +1,5 @@
+create table pending_restores
+(
+    chunk_uuid   varchar primary key,
+    requested_at varchar -- when the restore of the archived chunk was first requested
+);
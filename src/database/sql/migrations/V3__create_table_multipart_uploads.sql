@@ -0,0 +1,15 @@
+create table multipart_uploads
+(
+    chunk_uuid varchar primary key,
+    upload_id  varchar,
+    size       number -- size of the uploaded data, i.e. the encrypted chunk
+);
+
+create table multipart_upload_parts
+(
+    upload_id   varchar,
+    part_number number,
+    e_tag       varchar,
+    sha256      varchar, -- base64 encoded sum of the part, as sent to the cloud
+    primary key (upload_id, part_number)
+);
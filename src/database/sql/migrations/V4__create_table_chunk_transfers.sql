@@ -0,0 +1,8 @@
+create table chunk_transfers
+(
+    destination    varchar,
+    chunk_uuid     varchar,
+    sha256         varchar, -- sum of the copied object, i.e. the encrypted chunk
+    transferred_at datetime,
+    primary key (destination, chunk_uuid)
+);
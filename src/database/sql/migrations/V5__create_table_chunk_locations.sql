@@ -0,0 +1,16 @@
+create table chunk_locations
+(
+    chunk_uuid  varchar,
+    store       varchar, -- name of the store holding the chunk
+    status      varchar, -- upload status: PENDING, DONE
+    size        number,  -- size of the encrypted data, as stored
+    etag        varchar, -- as returned by the store, if any
+    uploaded_at datetime,
+    primary key (chunk_uuid, store)
+);
+
+-- chunks pushed so far went to the one store there was
+insert into chunk_locations (chunk_uuid, store, status, size)
+select uuid, 'default', status, size
+from chunks
+where status = 'DONE';
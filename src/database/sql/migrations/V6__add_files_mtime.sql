@@ -0,0 +1 @@
+alter table files add column mtime number; -- last modification time of the file, in seconds since epoch
@@ -0,0 +1 @@
+alter table chunk_locations add column retain_until number; -- the chunk cannot be deleted before, in seconds since epoch
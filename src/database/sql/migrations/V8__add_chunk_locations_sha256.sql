@@ -0,0 +1 @@
+alter table chunk_locations add column sha256 text; -- the sha-256 sum of the cipher text, as stored
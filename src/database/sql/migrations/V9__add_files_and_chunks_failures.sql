@@ -0,0 +1,7 @@
+alter table files add column error varchar;                  -- last push error
+alter table files add column attempts number not null default 0; -- failed push attempts
+alter table files add column failed_at datetime;             -- last failed push attempt
+
+alter table chunks add column error varchar;
+alter table chunks add column attempts number not null default 0;
+alter table chunks add column failed_at datetime;
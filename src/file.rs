@@ -1,10 +1,21 @@
 use anyhow::{bail, Error, Result};
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
+use std::fs::Metadata;
+use std::time::UNIX_EPOCH;
 
 pub mod repository;
 
-#[derive(Debug)]
+/// The last modification time, in seconds since epoch, as recorded by crawl.
+pub fn mtime(metadata: &Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs())
+}
+
+#[derive(Debug, Clone)]
 pub enum Mode {
     Chunked,
     // todo rename
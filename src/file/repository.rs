@@ -4,10 +4,11 @@ use anyhow::{bail, Result};
 use fallible_iterator::FallibleIterator;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params_from_iter, OptionalExtension, Row};
+use rusqlite::{params_from_iter, OptionalExtension, Row, ToSql};
+use std::fs::Metadata;
 use uuid::Uuid;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File {
     pub uuid: Uuid,
     pub path: String,
@@ -15,6 +16,24 @@ pub struct File {
     pub sha256: String,
     pub chunks: u64,
     pub mode: Mode,
+    /// last modification time, in seconds since epoch; unknown for files crawled by older versions
+    pub mtime: Option<u64>,
+}
+
+impl File {
+    /// Whether the file on disk no longer matches what crawl recorded.
+    pub fn changed(&self, metadata: &Metadata) -> bool {
+        self.differs(metadata.len(), super::mtime(metadata))
+    }
+
+    /// Whether a file of that size and mtime does not match what crawl recorded.
+    pub fn differs(&self, size: u64, mtime: Option<u64>) -> bool {
+        size != self.size
+            || matches!(
+                (self.mtime, mtime),
+                (Some(recorded), Some(actual)) if recorded != actual
+            )
+    }
 }
 
 impl From<&Row<'_>> for File {
@@ -26,6 +45,27 @@ impl From<&Row<'_>> for File {
             size: row.get(3).unwrap(),
             chunks: row.get(4).unwrap(),
             mode: row.get(5).unwrap(),
+            mtime: row.get(6).unwrap(),
+        }
+    }
+}
+
+/// A file whose last push attempt failed.
+#[derive(Debug)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: Option<String>,
+    pub attempts: u64,
+    pub failed_at: Option<String>,
+}
+
+impl From<&Row<'_>> for FailedFile {
+    fn from(row: &Row<'_>) -> Self {
+        FailedFile {
+            path: row.get(0).unwrap(),
+            error: row.get(1).unwrap(),
+            attempts: row.get(2).unwrap(),
+            failed_at: row.get(3).unwrap(),
         }
     }
 }
@@ -43,12 +83,13 @@ impl Repository {
         self.pool.get()?.execute(
             include_str!("sql/insert.sql"),
             &[
-                (":uuid", &file.uuid.to_string()),
+                (":uuid", &file.uuid.to_string() as &dyn ToSql),
                 (":path", &file.path),
                 (":sha256", &file.sha256),
                 (":size", &file.size.to_string()),
                 (":chunks", &file.chunks.to_string()),
                 (":mode", &Into::<&str>::into(&file.mode).to_string()),
+                (":mtime", &file.mtime),
             ],
         )?;
 
@@ -67,6 +108,17 @@ impl Repository {
             .optional()?)
     }
 
+    /// Records a failed push attempt. A done file stays done, as it failed to be pushed to
+    /// another store.
+    pub fn mark_failed(&self, uuid: &Uuid, error: &str) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/mark_failed.sql"),
+            &[(":uuid", &uuid.to_string()), (":error", &error.to_string())],
+        )?;
+
+        Ok(())
+    }
+
     pub fn find_by_uuid(&self, uuid: &Uuid) -> Result<Option<File>> {
         Ok(self
             .pool
@@ -109,6 +161,27 @@ impl Repository {
         Ok(rows.map(|row| Ok(row.into())).collect()?)
     }
 
+    /// Finds the files having chunks that are not in `store` yet, nor were moved from it. Files
+    /// that already failed `max_attempts` times, if set, are skipped.
+    pub fn find_by_mode_missing_in_store(
+        &self,
+        mode: Mode,
+        store: &str,
+        max_attempts: Option<u64>,
+    ) -> Result<Vec<File>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_by_mode_missing_in_store.sql"))?;
+
+        let rows = stmt.query(&[
+            (":mode", &mode as &dyn ToSql),
+            (":store", &store),
+            (":max_attempts", &max_attempts),
+        ])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
     pub fn mark_done(&self, uuid: &Uuid, sha256: &str) -> Result<()> {
         match self.pool.get()?.execute(
             include_str!("sql/mark_done.sql"),
@@ -122,6 +195,29 @@ impl Repository {
         }
     }
 
+    pub fn update_mtime(&self, uuid: &Uuid, mtime: u64) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/update_mtime.sql"),
+            &[(":uuid", &uuid.to_string()), (":mtime", &mtime.to_string())],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records the new size and mtime of a file that changed before it was done, and resets it
+    /// to pending. Returns false if the file is already done.
+    pub fn replan(&self, file: &File) -> Result<bool> {
+        Ok(self.pool.get()?.execute(
+            include_str!("sql/replan.sql"),
+            &[
+                (":uuid", &file.uuid.to_string() as &dyn ToSql),
+                (":size", &file.size.to_string()),
+                (":chunks", &file.chunks.to_string()),
+                (":mtime", &file.mtime),
+            ],
+        )? == 1)
+    }
+
     pub fn mark_aggregated(&self, uuid: &Uuid) -> Result<()> {
         match self.pool.get()?.execute(
             include_str!("sql/mark_aggregated.sql"),
@@ -150,6 +246,36 @@ impl Repository {
         Ok(stmt.query_row(&[(":status", &status)], |row| row.get::<_, u64>(0))?)
     }
 
+    /// Returns the count and total size of the files having chunks that are not in `store` yet,
+    /// skipping those that already failed `max_attempts` times, if set.
+    pub fn count_missing_in_store(
+        &self,
+        store: &str,
+        max_attempts: Option<u64>,
+    ) -> Result<(u64, u64)> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/count_missing_in_store.sql"))?;
+
+        Ok(stmt.query_row(
+            &[
+                (":store", &store as &dyn ToSql),
+                (":max_attempts", &max_attempts),
+            ],
+            |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)),
+        )?)
+    }
+
+    pub fn find_failed(&self) -> Result<Vec<FailedFile>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_failed.sql"))?;
+
+        let rows = stmt.query([])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
     pub fn count_bytes_by_status(&self, status: Status) -> Result<u64> {
         let connection = self.pool.get()?;
 
@@ -0,0 +1,12 @@
+select count(*), coalesce(sum(size), 0)
+from files f
+where (:max_attempts is null or status != 'FAILED' or attempts < :max_attempts)
+  and mode in ('CHUNKED', 'AGGREGATE')
+  and exists(select 1
+             from chunks c
+             where c.file_uuid = f.uuid
+               and not exists(select 1
+                              from chunk_locations l
+                              where l.chunk_uuid = c.uuid
+                                and l.store = :store
+                                and l.status in ('DONE', 'MOVED')))
\ No newline at end of file
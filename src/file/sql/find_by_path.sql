@@ -1 +1 @@
-select uuid, path, sha256, size, chunks, mode from files where path=:path
\ No newline at end of file
+select uuid, path, sha256, size, chunks, mode, mtime from files where path=:path
\ No newline at end of file
@@ -1 +1 @@
-select uuid, path, sha256, size, chunks, mode from files where status=:status and mode=:mode
\ No newline at end of file
+select uuid, path, sha256, size, chunks, mode, mtime from files where status=:status and mode=:mode
\ No newline at end of file
@@ -1,3 +1,3 @@
-select uuid, path, sha256, size, chunks, mode
+select uuid, path, sha256, size, chunks, mode, mtime
 from files
 where uuid = :uuid
\ No newline at end of file
@@ -0,0 +1,4 @@
+select path, error, attempts, failed_at
+from files
+where status = 'FAILED'
+order by failed_at, path
\ No newline at end of file
@@ -1,2 +1,2 @@
-insert into files (uuid, path, sha256, size, chunks, status, mode)
-values (:uuid, :path, :sha256, :size, :chunks, 'PENDING', :mode)
\ No newline at end of file
+insert into files (uuid, path, sha256, size, chunks, status, mode, mtime)
+values (:uuid, :path, :sha256, :size, :chunks, 'PENDING', :mode, :mtime)
\ No newline at end of file
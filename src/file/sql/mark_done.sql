@@ -1 +1 @@
-update files set status='DONE', sha256=:sha256 where uuid=:uuid
\ No newline at end of file
+update files set status='DONE', error=null, sha256=:sha256 where uuid=:uuid
\ No newline at end of file
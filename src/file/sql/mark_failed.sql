@@ -0,0 +1,6 @@
+update files
+set status=case when status = 'DONE' then status else 'FAILED' end,
+    error=:error,
+    attempts=attempts + 1,
+    failed_at=datetime('now')
+where uuid=:uuid
\ No newline at end of file
@@ -0,0 +1,11 @@
+update files
+set size=:size,
+    chunks=:chunks,
+    mtime=:mtime,
+    sha256='',
+    status='PENDING',
+    error=null,
+    attempts=0,
+    failed_at=null
+where uuid=:uuid
+  and status != 'DONE'
\ No newline at end of file
@@ -0,0 +1 @@
+update files set mtime=:mtime where uuid=:uuid
\ No newline at end of file
@@ -25,6 +25,11 @@ impl ChunkedSha256 {
         }
     }
 
+    /// The count of blocks hashed so far.
+    pub fn blocks(&self) -> u64 {
+        self.next_block
+    }
+
     pub fn finalize(&mut self) -> Option<String> {
         if self.pending_blocks.is_empty() {
             Some(format!("{:x}", self.hasher.finalize_reset()))
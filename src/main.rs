@@ -3,22 +3,29 @@ extern crate core;
 use crate::chunk::repository::Repository as ChunksRepository;
 use crate::config::Config;
 use crate::controller::json::{export, import};
-use crate::controller::{crawl, ls, mount};
-use crate::controller::{push, unwrap};
+use crate::controller::{backup, crawl, ls, mount};
+use crate::controller::{push, restore, tier, transfer, unwrap};
 use crate::database::PooledSqliteConnectionManager;
 use crate::error::Error;
 use crate::file::repository::Repository as FilesRepository;
 use crate::pgp::Pgp;
-use crate::store::Store;
-use crate::thread_pool::ThreadPool;
-use anyhow::{bail, Result};
-use clap::{command, Arg, Command};
+use anyhow::{anyhow, bail, Context, Result};
+use byte_unit::Byte;
+use clap::{command, Arg, ArgMatches, Command};
 use clap_complete::{generate, Shell};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use tokio::runtime::Builder;
 
 mod aggregate;
 mod chunk;
+mod chunk_location;
+mod chunk_transfer;
 mod config;
 mod controller;
 mod database;
@@ -27,10 +34,13 @@ mod file;
 mod fuse;
 mod hash;
 mod metrics;
+mod multipart_upload;
+mod pending_restore;
 mod pgp;
 mod status;
 mod store;
-mod thread_pool;
+#[cfg(test)]
+mod tests;
 
 fn main() {
     std::process::exit(match run() {
@@ -42,6 +52,52 @@ fn main() {
     })
 }
 
+/// Sets the flag on the first SIGINT or SIGTERM and exits on the second one.
+fn interrupt_on_signals(interrupted: &Arc<AtomicBool>) -> Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        // registered first, so that it only sees the flag set by a previous signal
+        flag::register_conditional_shutdown(signal, 1, interrupted.clone())
+            .with_context(|| format!("Failed to register handler for signal {}", signal))?;
+        flag::register(signal, interrupted.clone())
+            .with_context(|| format!("Failed to register handler for signal {}", signal))?;
+    }
+    Ok(())
+}
+
+/// The limits given by `--max-bytes` and `--max-duration`.
+fn quota(args: &ArgMatches) -> Result<push::Quota> {
+    Ok(push::Quota {
+        bytes: args
+            .value_of("max-bytes")
+            .map(|bytes| {
+                Byte::from_str(bytes)
+                    .map(|bytes| bytes.get_bytes() as u64)
+                    .map_err(|e| anyhow!("Invalid --max-bytes: {}", e))
+            })
+            .transpose()?,
+        duration: args
+            .value_of("max-duration")
+            .map(|duration| {
+                push::parse_duration(duration).with_context(|| "Invalid --max-duration")
+            })
+            .transpose()?,
+    })
+}
+
+/// `None` when the failed files are retried whatever their attempts count.
+fn max_attempts(args: &ArgMatches) -> Result<Option<u64>> {
+    if args.is_present("retry-failed") {
+        return Ok(None);
+    }
+    args.value_of("max-attempts")
+        .map(|attempts| {
+            attempts
+                .parse()
+                .with_context(|| format!("Invalid --max-attempts: {}", attempts))
+        })
+        .transpose()
+}
+
 fn run() -> Result<()> {
     pretty_env_logger::init();
 
@@ -70,6 +126,42 @@ fn run() -> Result<()> {
             }
             Ok(())
         }
+        Some(("backup", args)) => {
+            let config = match args.value_of("store") {
+                Some(store) => config.with_store(store)?,
+                None => config,
+            };
+            let backup_config = backup::Config {
+                crawl: crawl::Config {
+                    root_folder: config.get_root_path()?,
+                    chunk_size: config.get_chunk_size().get_bytes() as u64,
+                    aggregate_min_size: config.get_aggregate_min_size().get_bytes() as u64,
+                    aggregate_size: config.get_aggregate_size().get_bytes() as u64,
+                    ignored_files: config.get_ignored_files()?,
+                },
+                push: push::Config {
+                    root_folder: config.get_root_path()?,
+                    store: config.get_store_name(),
+                    max_attempts: max_attempts(args)?,
+                    interrupted: Arc::new(AtomicBool::new(false)),
+                    parallelism: config.get_push_parallelism(),
+                    memory_limit: config
+                        .get_memory_limit()
+                        .map(|limit| limit.get_bytes() as u64),
+                    policy: config.get_push_policy()?,
+                    quota: quota(args)?,
+                },
+            };
+            interrupt_on_signals(&backup_config.push.interrupted)?;
+            let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+            backup::execute(
+                backup_config,
+                sqlite.clone(),
+                Pgp::try_from(&config)?,
+                store::new(&config, &sqlite)?,
+                Builder::new_multi_thread().enable_all().build()?,
+            )
+        }
         Some(("crawl", _args)) => crawl::execute(
             crawl::Config {
                 root_folder: config.get_root_path()?,
@@ -83,30 +175,114 @@ fn run() -> Result<()> {
         Some(("export", _args)) => {
             export::execute(PooledSqliteConnectionManager::try_from(&config)?)
         }
-        Some(("mount", args)) => mount::execute(
-            mount::Config {
-                cache_folder: config.get_cache_folder(),
-                mountpoint: args.value_of("mountpoint").unwrap(),
-            },
-            PooledSqliteConnectionManager::try_from(&config)?,
-            Pgp::try_from(&config)?,
-            Box::<dyn Store>::try_from(&config)?,
-            Builder::new_current_thread().enable_all().build()?,
-        ),
+        Some(("mount", args)) => {
+            let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+            mount::execute(
+                mount::Config {
+                    cache_folder: config.get_cache_folder(),
+                    mountpoint: args.value_of("mountpoint").unwrap(),
+                },
+                sqlite.clone(),
+                Pgp::try_from(&config)?,
+                store::catalog(&config, &sqlite)?,
+                Builder::new_current_thread().enable_all().build()?,
+            )
+        }
         Some(("import", _args)) => {
             import::execute(PooledSqliteConnectionManager::try_from(&config)?)
         }
-        Some(("ls", _args)) => ls::execute(PooledSqliteConnectionManager::try_from(&config)?),
-        Some(("push", _args)) => push::execute(
-            push::Config {
-                root_folder: config.get_root_path()?,
+        Some(("ls", args)) => ls::execute(
+            ls::Config {
+                failed: args.is_present("failed"),
             },
             PooledSqliteConnectionManager::try_from(&config)?,
-            Pgp::try_from(&config)?,
-            Box::<dyn Store>::try_from(&config)?,
-            ThreadPool::new(config.get_max_workers_count(), config.get_max_queue_size()),
-            Builder::new_current_thread().enable_all().build()?,
         ),
+        Some(("push", args)) => {
+            let config = match args.value_of("store") {
+                Some(store) => config.with_store(store)?,
+                None => config,
+            };
+            let push_config = push::Config {
+                root_folder: config.get_root_path()?,
+                store: config.get_store_name(),
+                max_attempts: max_attempts(args)?,
+                interrupted: Arc::new(AtomicBool::new(false)),
+                parallelism: config.get_push_parallelism(),
+                memory_limit: config
+                    .get_memory_limit()
+                    .map(|limit| limit.get_bytes() as u64),
+                policy: config.get_push_policy()?,
+                quota: quota(args)?,
+            };
+            if args.is_present("dry-run") {
+                // the store is not even created, as some check the bucket on creation
+                push::dry_run(
+                    push_config,
+                    PooledSqliteConnectionManager::try_from(&config)?,
+                )
+                .map(|_| ())
+            } else {
+                interrupt_on_signals(&push_config.interrupted)?;
+                let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+                push::execute(
+                    push_config,
+                    sqlite.clone(),
+                    Pgp::try_from(&config)?,
+                    store::new(&config, &sqlite)?,
+                    Builder::new_multi_thread().enable_all().build()?,
+                )
+            }
+        }
+        Some(("restore", args)) => {
+            let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+            restore::execute(
+                restore::Config {
+                    target_folder: args.value_of("target").unwrap(),
+                },
+                sqlite.clone(),
+                Pgp::try_from(&config)?,
+                store::catalog(&config, &sqlite)?,
+                Builder::new_current_thread().enable_all().build()?,
+            )
+        }
+        Some(("tier", _args)) => {
+            let rules = config.get_tier_rules()?;
+            let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+            let mut stores = HashMap::new();
+            for name in rules.iter().flat_map(|rule| [rule.from, rule.to]) {
+                if !stores.contains_key(name) {
+                    stores.insert(
+                        name.to_string(),
+                        store::new(&config.with_store(name)?, &sqlite)?,
+                    );
+                }
+            }
+            tier::execute(
+                tier::Config { rules },
+                sqlite,
+                stores,
+                Builder::new_current_thread().enable_all().build()?,
+            )
+        }
+        Some(("transfer", args)) => {
+            let from = args.value_of("from").unwrap();
+            let to = args.value_of("to").unwrap();
+            let from_config =
+                Config::new(from).with_context(|| format!("Failed to load {}", from))?;
+            let to_config = Config::new(to).with_context(|| format!("Failed to load {}", to))?;
+            let sqlite = PooledSqliteConnectionManager::try_from(&config)?;
+            transfer::execute(
+                transfer::Config {
+                    destination: &fs::canonicalize(to)?.to_string_lossy(),
+                    source_store: from_config.get_store_name(),
+                    destination_store: to_config.get_store_name(),
+                },
+                sqlite.clone(),
+                store::new(&from_config, &sqlite)?,
+                store::new(&to_config, &sqlite)?,
+                Builder::new_current_thread().enable_all().build()?,
+            )
+        }
         Some(("unwrap", args)) => {
             unwrap::execute(args.value_of("path").unwrap(), Pgp::try_from(&config)?)
         }
@@ -115,6 +291,37 @@ fn run() -> Result<()> {
     }
 }
 
+/// The options of push, also used by backup.
+fn push_args() -> [Arg<'static>; 5] {
+    [
+        Arg::new("store")
+            .help("Name of the store to push to, among `stores`; defaults to `store`")
+            .long("store")
+            .short('s')
+            .takes_value(true)
+            .forbid_empty_values(true),
+        Arg::new("max-attempts")
+            .help("Skips the files that failed to be pushed that many times")
+            .long("max-attempts")
+            .takes_value(true)
+            .forbid_empty_values(true)
+            .default_value("3"),
+        Arg::new("retry-failed")
+            .help("Retries the files that failed to be pushed, however many times they did")
+            .long("retry-failed"),
+        Arg::new("max-bytes")
+            .help("Stops queueing chunks once that many bytes are uploaded, e.g. 200GB; the chunks already in the store do not count, and the first chunk is pushed anyway")
+            .long("max-bytes")
+            .takes_value(true)
+            .forbid_empty_values(true),
+        Arg::new("max-duration")
+            .help("Stops queueing chunks after that long, e.g. 6h or 1h30m")
+            .long("max-duration")
+            .takes_value(true)
+            .forbid_empty_values(true),
+    ]
+}
+
 fn cli() -> Command<'static> {
     command!()
         .subcommand_required(true)
@@ -140,6 +347,11 @@ fn cli() -> Command<'static> {
                         .possible_values(Shell::possible_values()),
                 ),
         )
+        .subcommand(
+            Command::new("backup")
+                .about("Crawl and push at the same time")
+                .args(push_args()),
+        )
         .subcommand(Command::new("crawl").about("Crawl to discover files to push"))
         .subcommand(
             Command::new("export").about("Export files database to JSON (writes to stdout)"),
@@ -158,8 +370,61 @@ fn cli() -> Command<'static> {
                 ),
         )
         .subcommand(Command::new("import").about("Import database from JSON (reads from stdin)"))
-        .subcommand(Command::new("ls").about("Lists files from database"))
-        .subcommand(Command::new("push").about("Copy crawled files to cloud"))
+        .subcommand(
+            Command::new("ls").about("Lists files from database").arg(
+                Arg::new("failed")
+                    .help("Lists the files that failed to be pushed, with the last error")
+                    .long("failed"),
+            ),
+        )
+        .subcommand(
+            Command::new("push")
+                .about("Copy crawled files to cloud")
+                .args(push_args())
+                .arg(
+                    Arg::new("dry-run")
+                        .help("Reports what would be pushed, without encrypting nor uploading anything")
+                        .long("dry-run"),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restore pushed files from cloud")
+                .arg(
+                    Arg::new("target")
+                        .help("Folder where to restore files")
+                        .long("target")
+                        .short('t')
+                        .required(true)
+                        .takes_value(true)
+                        .forbid_empty_values(true),
+                ),
+        )
+        .subcommand(
+            Command::new("tier").about("Move chunks of old files to colder stores, as per `tiers`"),
+        )
+        .subcommand(
+            Command::new("transfer")
+                .about("Copy pushed chunks from one store to another")
+                .arg(
+                    Arg::new("from")
+                        .help("Configuration file of the source store")
+                        .long("from")
+                        .short('f')
+                        .required(true)
+                        .takes_value(true)
+                        .forbid_empty_values(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .help("Configuration file of the destination store")
+                        .long("to")
+                        .short('t')
+                        .required(true)
+                        .takes_value(true)
+                        .forbid_empty_values(true),
+                ),
+        )
         .subcommand(
             Command::new("unwrap")
                 .about("Unwrap chunk to return raw data")
@@ -59,7 +59,7 @@ impl Collector {
                                 Ok(Metric::End) => {
                                     log::debug!("end");
                                     running = false;
-                                    break 0;
+                                    break elapsed;
                                 }
                                 Err(TryRecvError::Empty) => {
                                     thread::sleep(Duration::from_millis(SLEEP_MS as u64))
@@ -68,7 +68,8 @@ impl Collector {
                             }
                         };
 
-                        if elapsed > SLEEP_MS {
+                        // the last point is always reported
+                        if elapsed > SLEEP_MS || !running {
                             timestamp = Self::timestamp();
                             log::info!("{}", metrics.point(elapsed, timestamp - start_timestamp));
                         }
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use fallible_iterator::FallibleIterator;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{OptionalExtension, Row};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct MultipartUpload {
+    pub chunk_uuid: Uuid,
+    pub upload_id: String,
+    /// size of the uploaded data
+    pub size: u64,
+}
+
+impl From<&Row<'_>> for MultipartUpload {
+    fn from(row: &Row<'_>) -> Self {
+        MultipartUpload {
+            chunk_uuid: Uuid::parse_str(&row.get::<_, String>(0).unwrap()).unwrap(),
+            upload_id: row.get(1).unwrap(),
+            size: row.get(2).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Part {
+    pub upload_id: String,
+    pub part_number: i32,
+    pub e_tag: String,
+    /// base64 encoded sha-256 sum of the part
+    pub sha256: String,
+}
+
+impl From<&Row<'_>> for Part {
+    fn from(row: &Row<'_>) -> Self {
+        Part {
+            upload_id: row.get(0).unwrap(),
+            part_number: row.get(1).unwrap(),
+            e_tag: row.get(2).unwrap(),
+            sha256: row.get(3).unwrap(),
+        }
+    }
+}
+
+pub struct Repository {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Repository {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    pub fn insert(&self, upload: &MultipartUpload) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/insert.sql"),
+            &[
+                (":chunk_uuid", &upload.chunk_uuid.to_string()),
+                (":upload_id", &upload.upload_id),
+                (":size", &upload.size.to_string()),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn find_by_chunk_uuid(&self, chunk_uuid: &Uuid) -> Result<Option<MultipartUpload>> {
+        Ok(self
+            .pool
+            .get()?
+            .query_row(
+                include_str!("sql/find_by_chunk_uuid.sql"),
+                &[(":chunk_uuid", &chunk_uuid.to_string())],
+                |row| Ok(row.into()),
+            )
+            .optional()?)
+    }
+
+    pub fn find_all(&self) -> Result<Vec<MultipartUpload>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_all.sql"))?;
+
+        let rows = stmt.query([])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+
+    /// Deletes the upload and its parts.
+    pub fn delete(&self, upload_id: &str) -> Result<()> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            include_str!("sql/delete_parts.sql"),
+            &[(":upload_id", upload_id)],
+        )?;
+        connection.execute(include_str!("sql/delete.sql"), &[(":upload_id", upload_id)])?;
+
+        Ok(())
+    }
+
+    pub fn insert_part(&self, part: &Part) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/insert_part.sql"),
+            &[
+                (":upload_id", &part.upload_id),
+                (":part_number", &part.part_number.to_string()),
+                (":e_tag", &part.e_tag),
+                (":sha256", &part.sha256),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn find_parts_by_upload_id(&self, upload_id: &str) -> Result<Vec<Part>> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare(include_str!("sql/find_parts_by_upload_id.sql"))?;
+
+        let rows = stmt.query(&[(":upload_id", upload_id)])?;
+
+        Ok(rows.map(|row| Ok(row.into())).collect()?)
+    }
+}
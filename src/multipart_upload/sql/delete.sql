@@ -0,0 +1 @@
+delete from multipart_uploads where upload_id = :upload_id
\ No newline at end of file
@@ -0,0 +1 @@
+delete from multipart_upload_parts where upload_id = :upload_id
\ No newline at end of file
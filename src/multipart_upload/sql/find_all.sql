@@ -0,0 +1,2 @@
+select chunk_uuid, upload_id, size
+from multipart_uploads
\ No newline at end of file
@@ -0,0 +1,3 @@
+select chunk_uuid, upload_id, size
+from multipart_uploads
+where chunk_uuid = :chunk_uuid
\ No newline at end of file
@@ -0,0 +1,4 @@
+select upload_id, part_number, e_tag, sha256
+from multipart_upload_parts
+where upload_id = :upload_id
+order by part_number
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert into multipart_uploads (chunk_uuid, upload_id, size)
+values (:chunk_uuid, :upload_id, :size)
\ No newline at end of file
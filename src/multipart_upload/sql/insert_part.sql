@@ -0,0 +1,2 @@
+insert or replace into multipart_upload_parts (upload_id, part_number, e_tag, sha256)
+values (:upload_id, :part_number, :e_tag, :sha256)
\ No newline at end of file
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use uuid::Uuid;
+
+pub struct Repository {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Repository {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    pub fn insert(&self, chunk_uuid: &Uuid) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/insert.sql"),
+            &[(":chunk_uuid", &chunk_uuid.to_string())],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete(&self, chunk_uuid: &Uuid) -> Result<()> {
+        self.pool.get()?.execute(
+            include_str!("sql/delete.sql"),
+            &[(":chunk_uuid", &chunk_uuid.to_string())],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn count(&self) -> Result<u64> {
+        let connection = self.pool.get()?;
+
+        let mut stmt = connection.prepare("select count(*) from pending_restores")?;
+
+        Ok(stmt.query_row([], |row| row.get::<_, u64>(0))?)
+    }
+}
@@ -0,0 +1 @@
+delete from pending_restores where chunk_uuid = :chunk_uuid
\ No newline at end of file
@@ -0,0 +1,2 @@
+insert or ignore into pending_restores (chunk_uuid, requested_at)
+values (:chunk_uuid, datetime('now'))
\ No newline at end of file
@@ -94,6 +94,22 @@ impl Pgp {
         }
     }
 
+    /// Generates a passphrase-protected key, for tests.
+    #[cfg(test)]
+    pub fn generate_key(path: &std::path::Path, passphrase: &str) -> Result<()> {
+        use sequoia_openpgp::cert::CertBuilder;
+        use sequoia_openpgp::serialize::Serialize;
+        use std::fs::File;
+
+        let (cert, _) = CertBuilder::new()
+            .add_userid("fs2cloud")
+            .add_storage_encryption_subkey()
+            .set_password(Some(passphrase.into()))
+            .generate()?;
+        cert.as_tsk().serialize(&mut File::create(path)?)?;
+        Ok(())
+    }
+
     pub fn encrypt<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<usize>
     where
         R: Read,
@@ -8,6 +8,10 @@ use std::fmt::{Display, Formatter};
 pub enum Status {
     Pending,
     Done,
+    /// for chunk locations: the chunk was moved to another store
+    Moved,
+    /// the last push attempt failed; see the error recorded with it
+    Failed,
 }
 
 impl Display for Status {
@@ -15,6 +19,8 @@ impl Display for Status {
         match self {
             Status::Pending => write!(f, "PENDING"),
             Status::Done => write!(f, "DONE"),
+            Status::Moved => write!(f, "MOVED"),
+            Status::Failed => write!(f, "FAILED"),
         }
     }
 }
@@ -24,6 +30,8 @@ impl From<&Status> for &str {
         match mode {
             Status::Pending => "PENDING",
             Status::Done => "DONE",
+            Status::Moved => "MOVED",
+            Status::Failed => "FAILED",
         }
     }
 }
@@ -35,6 +43,8 @@ impl TryFrom<&str> for Status {
         match value {
             "PENDING" => Ok(Status::Pending),
             "DONE" => Ok(Status::Done),
+            "MOVED" => Ok(Status::Moved),
+            "FAILED" => Ok(Status::Failed),
             s => bail!("Not a status: {}", s),
         }
     }
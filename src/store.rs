@@ -1,67 +1,318 @@
+use crate::chunk_location::repository::Repository as ChunkLocationsRepository;
+use crate::store::azure_blob::AzureBlob;
+use crate::store::catalog::Catalog;
+use crate::store::command::Command;
+use crate::store::gcs::Gcs;
 use crate::store::local::Local;
 use crate::store::log::Log;
+use crate::store::memory::Memory;
+use crate::store::retention::Retention;
 use crate::store::s3::S3;
 use crate::store::s3_official::S3Official;
-use crate::Config;
-use anyhow::{Context, Error, Result};
+use crate::store::sftp::Sftp;
+use crate::store::webdav::WebDav;
+use crate::{Config, PooledSqliteConnectionManager};
+use anyhow::{bail, Context, Error, Result};
 use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use uuid::Uuid;
 
+pub mod azure_blob;
+pub mod catalog;
+#[cfg(any(test, feature = "chaos"))]
+pub mod chaos;
+pub mod command;
+pub mod gcs;
 pub mod local;
 pub mod log;
+pub mod memory;
+pub mod retention;
 pub mod s3;
 pub mod s3_official;
+pub mod sftp;
+mod token;
+pub mod webdav;
 
 #[async_trait]
 pub trait Store: Send + Sync {
-    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<()>;
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored>;
 
     async fn get(&self, object_id: Uuid) -> Result<Vec<u8>>;
+
+    /// Describes the object without downloading it; `None` if the store does not hold it.
+    async fn head(&self, _object_id: Uuid) -> Result<Option<Head>> {
+        Err(Unsupported("Checking objects").into())
+    }
+
+    /// Lists the ids of the objects in the store.
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        Err(Unsupported("Listing objects").into())
+    }
+
+    async fn delete(&self, _object_id: Uuid) -> Result<()> {
+        Err(Unsupported("Deleting objects").into())
+    }
+
+    /// Cleans up what interrupted runs left behind in the store.
+    async fn cleanup(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What the store reports about an object it stored.
+#[derive(Debug, Default)]
+pub struct Stored {
+    pub etag: Option<String>,
+    /// the object cannot be deleted before that time, in seconds since epoch
+    pub retain_until: Option<u64>,
+    /// the size and sha-256 sum of what was stored, when it differs from the data given, e.g. when
+    /// an interrupted upload is resumed with the data of a previous run
+    pub resumed: Option<(u64, String)>,
+}
+
+/// What the store reports about an object it holds.
+#[derive(Debug)]
+pub struct Head {
+    pub size: u64,
+    /// the sha-256 sum of the object, when the store knows it
+    pub sha256: Option<String>,
+    pub etag: Option<String>,
+    /// the object cannot be deleted before that time, in seconds since epoch
+    pub retain_until: Option<u64>,
+}
+
+/// Returned by [`Store::get`] when the object is archived: a restore was requested and the object
+/// will be available later.
+#[derive(Debug)]
+pub struct RestorePending(pub Uuid);
+
+impl Display for RestorePending {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "object {} is being restored from archive", self.0)
+    }
+}
+
+impl std::error::Error for RestorePending {}
+
+impl RestorePending {
+    pub fn is(error: &Error) -> bool {
+        error.chain().any(|e| e.is::<RestorePending>())
+    }
+}
+
+/// Returned by the operations a store does not implement.
+#[derive(Debug)]
+pub struct Unsupported(pub &'static str);
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not supported by this store", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+impl Unsupported {
+    pub fn is(error: &Error) -> bool {
+        error.chain().any(|e| e.is::<Unsupported>())
+    }
 }
 
 pub enum StoreKind {
+    AzureBlob,
+    Chaos,
+    Command,
+    Gcs,
     Local,
     Log,
+    Memory,
     S3,
     S3Official,
+    Sftp,
+    WebDav,
 }
 
-pub fn new(config: &Config) -> Result<Box<dyn Store>> {
+/// Builds the configured store, which refuses to delete retained objects; `sqlite` is the database
+/// the catalog is in.
+pub fn new(config: &Config, sqlite: &PooledSqliteConnectionManager) -> Result<Box<dyn Store>> {
     fn s3(config: &Config) -> Result<Box<dyn Store>> {
         Ok(Box::from(
-            S3::new(
-                config.get_s3_region()?,
-                config.get_s3_bucket()?,
-                config.get_s3_access_key(),
-                config.get_s3_secret_key(),
-            )
+            S3::new(s3::Config {
+                region: config.get_s3_region()?,
+                bucket: config.get_s3_bucket()?,
+                access_key: config.get_s3_access_key(),
+                secret_key: config.get_s3_secret_key(),
+                endpoint: config.get_s3_endpoint(),
+                path_style: config.get_s3_path_style(),
+                prefix: config.get_s3_prefix(),
+                sse: config.get_s3_sse(),
+                sse_kms_key_id: config.get_s3_sse_kms_key_id(),
+            })
             .with_context(|| "Error configuring S3")?,
         ))
     }
 
-    fn s3_official(config: &Config) -> Result<Box<dyn Store>> {
+    fn s3_official(
+        config: &Config,
+        sqlite: &PooledSqliteConnectionManager,
+    ) -> Result<Box<dyn Store>> {
         Ok(Box::from(
-            S3Official::new(
-                config.get_s3_official_bucket()?,
-                config.get_s3_official_multipart_part_size(),
-            )
+            S3Official::new(s3_official::Config {
+                bucket: config.get_s3_official_bucket()?,
+                endpoint: config.get_s3_official_endpoint(),
+                region: config.get_s3_official_region(),
+                prefix: config.get_s3_official_prefix(),
+                sse: config.get_s3_official_sse(),
+                sse_kms_key_id: config.get_s3_official_sse_kms_key_id(),
+                multipart_part_size: config.get_s3_official_multipart_part_size(),
+                multipart_concurrency: config.get_s3_official_multipart_concurrency(),
+                multipart_retries: config.get_s3_official_multipart_retries(),
+                multipart_spool: config.get_s3_official_multipart_spool(),
+                storage_class: config.get_s3_official_storage_class(),
+                storage_class_rules: config.get_s3_official_storage_class_rules()?,
+                restore_days: config.get_s3_official_restore_days(),
+                restore_tier: config.get_s3_official_restore_tier(),
+                object_lock_mode: config.get_s3_official_object_lock_mode(),
+                object_lock_days: config.get_s3_official_object_lock_days(),
+                sqlite: sqlite.clone(),
+            })
             .with_context(|| "Error configuring S3")?,
         ))
     }
 
-    match config.get_store_type() {
-        Ok(StoreKind::Log) => Ok(Box::new(Log::new())),
-        Ok(StoreKind::S3) => s3(config),
-        Ok(StoreKind::S3Official) => s3_official(config),
-        Ok(StoreKind::Local) => Ok(Box::new(Local::new(config.get_local_store_path()?)?)),
-        Err(e) => Err(e),
+    #[cfg(any(test, feature = "chaos"))]
+    fn chaos(config: &Config, sqlite: &PooledSqliteConnectionManager) -> Result<Box<dyn Store>> {
+        let inner = match config.get_chaos_store_type()? {
+            StoreKind::Chaos => bail!("Chaos store cannot wrap a chaos store"),
+            kind => build(config, sqlite, kind)?,
+        };
+        Ok(Box::new(chaos::Chaos::new(
+            inner,
+            chaos::Config {
+                latency: config.get_chaos_latency(),
+                error_rate: config.get_chaos_error_rate(),
+                forbidden_rate: config.get_chaos_forbidden_rate(),
+                truncate_rate: config.get_chaos_truncate_rate(),
+                corrupt_rate: config.get_chaos_corrupt_rate(),
+                seed: config.get_chaos_seed(),
+            },
+        )))
+    }
+
+    #[cfg(not(any(test, feature = "chaos")))]
+    fn chaos(_config: &Config, _sqlite: &PooledSqliteConnectionManager) -> Result<Box<dyn Store>> {
+        bail!("The chaos store is only available when built with the `chaos` feature")
+    }
+
+    fn command(config: &Config) -> Result<Box<dyn Store>> {
+        Ok(Box::new(Command::new(command::Config {
+            program: config.get_command_store_program()?,
+            args: config.get_command_store_args(),
+        })))
+    }
+
+    fn webdav(config: &Config) -> Result<Box<dyn Store>> {
+        Ok(Box::new(
+            WebDav::new(webdav::Config {
+                url: config.get_webdav_url()?,
+                username: config.get_webdav_username(),
+                password: config.get_webdav_password(),
+                ca_certificate: config.get_webdav_ca_certificate(),
+                accept_invalid_certs: config.get_webdav_accept_invalid_certs(),
+            })
+            .with_context(|| "Error configuring WebDAV")?,
+        ))
+    }
+
+    fn azure_blob(config: &Config) -> Result<Box<dyn Store>> {
+        Ok(Box::new(
+            AzureBlob::new(azure_blob::Config {
+                account: config.get_azure_blob_account()?,
+                container: config.get_azure_blob_container()?,
+                endpoint: config.get_azure_blob_endpoint(),
+                prefix: config.get_azure_blob_prefix(),
+                access_key: config.get_azure_blob_access_key(),
+                sas_token: config.get_azure_blob_sas_token(),
+                block_size: config.get_azure_blob_block_size(),
+            })
+            .with_context(|| "Error configuring Azure Blob")?,
+        ))
+    }
+
+    fn gcs(config: &Config) -> Result<Box<dyn Store>> {
+        Ok(Box::new(
+            Gcs::new(gcs::Config {
+                bucket: config.get_gcs_bucket()?,
+                endpoint: config.get_gcs_endpoint(),
+                prefix: config.get_gcs_prefix(),
+                credentials: config.get_gcs_credentials(),
+                resumable_chunk_size: config.get_gcs_resumable_chunk_size(),
+            })
+            .with_context(|| "Error configuring GCS")?,
+        ))
+    }
+
+    fn sftp(config: &Config) -> Result<Box<dyn Store>> {
+        Ok(Box::new(Sftp::new(sftp::Config {
+            host: config.get_sftp_host()?,
+            port: config.get_sftp_port()?,
+            username: config.get_sftp_username(),
+            key_file: config.get_sftp_key_file(),
+            path: config.get_sftp_path()?,
+        })))
+    }
+
+    fn build(
+        config: &Config,
+        sqlite: &PooledSqliteConnectionManager,
+        kind: StoreKind,
+    ) -> Result<Box<dyn Store>> {
+        match kind {
+            StoreKind::AzureBlob => azure_blob(config),
+            StoreKind::Chaos => chaos(config, sqlite),
+            StoreKind::Command => command(config),
+            StoreKind::Gcs => gcs(config),
+            StoreKind::Log => Ok(Box::new(Log::new())),
+            StoreKind::Memory => Ok(Box::new(Memory::new())),
+            StoreKind::S3 => s3(config),
+            StoreKind::S3Official => s3_official(config, sqlite),
+            StoreKind::Sftp => sftp(config),
+            StoreKind::WebDav => webdav(config),
+            StoreKind::Local => Ok(Box::new(Local::new(config.get_local_store_path()?)?)),
+        }
     }
+
+    let store = build(config, sqlite, config.get_store_type()?)
+        .with_context(|| "Unable to instantiate store")?;
+    Ok(Box::new(Retention::new(
+        store,
+        config.get_store_name().to_string(),
+        ChunkLocationsRepository::new(sqlite.clone()),
+    )))
 }
 
-impl TryFrom<&Config> for Box<dyn Store> {
-    type Error = Error;
+/// Builds the store to read chunks from: `store` alone, or a [`Catalog`] over `store` and `stores`
+/// when there are several.
+pub fn catalog(config: &Config, sqlite: &PooledSqliteConnectionManager) -> Result<Box<dyn Store>> {
+    let names = config.get_stores_names();
+    if names.is_empty() {
+        return new(config, sqlite);
+    }
 
-    fn try_from(config: &Config) -> Result<Self, Self::Error> {
-        new(config).with_context(|| "Unable to instantiate store")
+    let mut stores = HashMap::new();
+    stores.insert(config.get_store_name().to_string(), new(config, sqlite)?);
+    for name in names {
+        stores.insert(
+            name.to_string(),
+            new(&config.with_store(name)?, sqlite)
+                .with_context(|| format!("Unable to instantiate store `{}`", name))?,
+        );
     }
+
+    Ok(Box::new(Catalog::new(
+        config.get_store_name().to_string(),
+        stores,
+        ChunkLocationsRepository::new(sqlite.clone()),
+    )))
 }
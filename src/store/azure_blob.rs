@@ -0,0 +1,664 @@
+use crate::store::token::{self, Token};
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use attohttpc::{Method, RequestBuilder, Response, StatusCode};
+use chrono::Utc;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ring::hmac;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use xmlparser::{ElementEnd, Token as XmlToken, Tokenizer};
+
+pub struct Config<'a> {
+    pub account: &'a str,
+    pub container: &'a str,
+    /// endpoint of the blob service, e.g. Azurite's; `https://<account>.blob.core.windows.net` if
+    /// not set
+    pub endpoint: Option<&'a str>,
+    /// prefix of the blobs names inside the container
+    pub prefix: Option<&'a str>,
+    pub access_key: Option<&'a str>,
+    pub sas_token: Option<&'a str>,
+    /// chunks larger than this are uploaded in blocks of this size; 0 to disable
+    pub block_size: u64,
+}
+
+/// Characters escaped in blob names; `/` separates virtual folders.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+const QUERY: &AsciiSet = &PATH.add(b'/');
+
+const API_VERSION: &str = "2020-10-02";
+
+/// Maximum size of a block
+/// source: https://learn.microsoft.com/en-us/rest/api/storageservices/put-block
+const MAX_BLOCK_SIZE: u64 = 4_000 * 1024 * 1024;
+
+pub struct AzureBlob {
+    client: Arc<Client>,
+    prefix: String,
+    block_size: usize,
+}
+
+struct Client {
+    account: String,
+    endpoint: String,
+    /// path part of the endpoint, part of the signed resource
+    endpoint_path: String,
+    container: String,
+    credentials: Credentials,
+    token: Token,
+}
+
+enum Credentials {
+    SharedKey(Vec<u8>),
+    Sas(String),
+    ServicePrincipal {
+        tenant_id: String,
+        client_id: String,
+        client_secret: String,
+    },
+    ManagedIdentity,
+}
+
+impl Credentials {
+    /// Looks for credentials in the configuration, then in the environment (`AZURE_STORAGE_KEY`,
+    /// `AZURE_STORAGE_SAS_TOKEN`, `AZURE_STORAGE_CONNECTION_STRING`, then the
+    /// `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and `AZURE_CLIENT_SECRET` of a service principal) and
+    /// falls back to the managed identity of the host.
+    fn find(config: &Config) -> Result<Credentials> {
+        fn shared_key(key: &str) -> Result<Credentials> {
+            Ok(Credentials::SharedKey(
+                base64::decode(key.trim()).with_context(|| "Invalid access key")?,
+            ))
+        }
+
+        if let Some(key) = config.access_key {
+            return shared_key(key);
+        }
+        if let Some(sas_token) = config.sas_token {
+            return Ok(Credentials::Sas(
+                sas_token.trim_start_matches('?').to_string(),
+            ));
+        }
+        if let Ok(key) = env::var("AZURE_STORAGE_KEY") {
+            return shared_key(&key);
+        }
+        if let Ok(sas_token) = env::var("AZURE_STORAGE_SAS_TOKEN") {
+            return Ok(Credentials::Sas(
+                sas_token.trim_start_matches('?').to_string(),
+            ));
+        }
+        if let Ok(connection_string) = env::var("AZURE_STORAGE_CONNECTION_STRING") {
+            for (key, value) in connection_string
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+            {
+                match key {
+                    "AccountKey" => return shared_key(value),
+                    "SharedAccessSignature" => return Ok(Credentials::Sas(value.to_string())),
+                    _ => {}
+                }
+            }
+        }
+        if let (Ok(tenant_id), Ok(client_id), Ok(client_secret)) = (
+            env::var("AZURE_TENANT_ID"),
+            env::var("AZURE_CLIENT_ID"),
+            env::var("AZURE_CLIENT_SECRET"),
+        ) {
+            return Ok(Credentials::ServicePrincipal {
+                tenant_id,
+                client_id,
+                client_secret,
+            });
+        }
+        Ok(Credentials::ManagedIdentity)
+    }
+}
+
+impl AzureBlob {
+    pub fn new(config: Config) -> Result<AzureBlob> {
+        let credentials = Credentials::find(&config)?;
+        let endpoint = config
+            .endpoint
+            .map(|e| e.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| format!("https://{}.blob.core.windows.net", config.account));
+
+        Ok(AzureBlob {
+            client: Arc::new(Client {
+                account: config.account.to_string(),
+                endpoint_path: endpoint
+                    .splitn(4, '/')
+                    .nth(3)
+                    .map(|path| format!("/{}", path))
+                    .unwrap_or_default(),
+                endpoint,
+                container: config.container.to_string(),
+                credentials,
+                token: Token::default(),
+            }),
+            prefix: config
+                .prefix
+                .map(|p| p.trim_matches('/'))
+                .filter(|p| !p.is_empty())
+                .map(|p| format!("{}/", p))
+                .unwrap_or_default(),
+            block_size: config.block_size.min(MAX_BLOCK_SIZE) as usize,
+        })
+    }
+
+    fn path(&self, uuid: Uuid) -> String {
+        format!("{}{}", self.prefix, uuid)
+    }
+
+    /// Runs a blocking operation of the client without blocking the runtime.
+    async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&Client) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || operation(&client)).await?
+    }
+}
+
+impl Client {
+    fn send(
+        &self,
+        method: Method,
+        blob: Option<&str>,
+        query: &[(&str, &str)],
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> Result<Response> {
+        let response = self.request(method, blob, query, headers, body)?;
+        if !response.is_success() {
+            bail!(
+                "Azure: error {} {}",
+                response.status(),
+                response
+                    .headers()
+                    .get("x-ms-error-code")
+                    .and_then(|code| code.to_str().ok())
+                    .unwrap_or_default()
+            );
+        }
+        Ok(response)
+    }
+
+    /// Sends the signed request, whatever the status of the response.
+    fn request(
+        &self,
+        method: Method,
+        blob: Option<&str>,
+        query: &[(&str, &str)],
+        headers: &[(&'static str, &str)],
+        body: &[u8],
+    ) -> Result<Response> {
+        let path = match blob {
+            Some(blob) => format!(
+                "{}/{}/{}",
+                self.endpoint_path,
+                self.container,
+                utf8_percent_encode(blob, PATH)
+            ),
+            None => format!("{}/{}", self.endpoint_path, self.container),
+        };
+        let mut query_string = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, utf8_percent_encode(v, QUERY)))
+            .collect::<Vec<String>>();
+        if let Credentials::Sas(sas_token) = &self.credentials {
+            query_string.push(sas_token.clone());
+        }
+        let url = format!(
+            "{}{}?{}",
+            self.endpoint,
+            &path[self.endpoint_path.len()..],
+            query_string.join("&")
+        );
+
+        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let mut ms_headers = vec![("x-ms-date", date.as_str()), ("x-ms-version", API_VERSION)];
+        ms_headers.extend(headers.iter().filter(|(k, _)| k.starts_with("x-ms-")));
+        let content_type = headers
+            .iter()
+            .find(|(k, _)| *k == "Content-Type")
+            .map(|(_, v)| *v)
+            .unwrap_or_default();
+
+        let mut request = RequestBuilder::new(method.clone(), url);
+        for (name, value) in ms_headers.iter().chain(headers.iter()) {
+            request = request.header(*name, *value);
+        }
+        request = match &self.credentials {
+            Credentials::SharedKey(key) => {
+                let string_to_sign = string_to_sign(
+                    method.as_str(),
+                    &self.account,
+                    &path,
+                    query,
+                    &ms_headers,
+                    body.len(),
+                    content_type,
+                );
+                let signature = hmac::sign(
+                    &hmac::Key::new(hmac::HMAC_SHA256, key),
+                    string_to_sign.as_bytes(),
+                );
+                request.header(
+                    "Authorization",
+                    format!(
+                        "SharedKey {}:{}",
+                        self.account,
+                        base64::encode(signature.as_ref())
+                    ),
+                )
+            }
+            Credentials::Sas(_) => request,
+            _ => request.header(
+                "Authorization",
+                format!("Bearer {}", self.token.get(|| self.fetch_token())?),
+            ),
+        };
+
+        Ok(request.bytes(body).send()?)
+    }
+
+    fn fetch_token(&self) -> Result<(String, Duration)> {
+        let response = match &self.credentials {
+            Credentials::ServicePrincipal {
+                tenant_id,
+                client_id,
+                client_secret,
+            } => RequestBuilder::new(
+                Method::POST,
+                format!(
+                    "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                    tenant_id
+                ),
+            )
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .text(format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}&scope={}",
+                utf8_percent_encode(client_id, QUERY),
+                utf8_percent_encode(client_secret, QUERY),
+                utf8_percent_encode("https://storage.azure.com/.default", QUERY)
+            ))
+            .send()?,
+            _ => RequestBuilder::new(
+                Method::GET,
+                "http://169.254.169.254/metadata/identity/oauth2/token?api-version=2018-02-01\
+                 &resource=https%3A%2F%2Fstorage.azure.com%2F",
+            )
+            .header("Metadata", "true")
+            .send()?,
+        };
+        if !response.is_success() {
+            bail!("Azure: failed to get access token: {}", response.status());
+        }
+        token::parse_response(&response.text_utf8()?)
+    }
+
+    fn put_blob(&self, blob: &str, data: &[u8]) -> Result<Option<String>> {
+        self.send(
+            Method::PUT,
+            Some(blob),
+            &[],
+            &[("x-ms-blob-type", "BlockBlob")],
+            data,
+        )
+        .map(|response| Self::etag(&response))
+    }
+
+    /// Uploads the data in blocks, then commits them.
+    fn put_blocks(&self, blob: &str, data: &[u8], block_size: usize) -> Result<Option<String>> {
+        let mut block_ids = Vec::new();
+        for (i, block) in data.chunks(block_size).enumerate() {
+            // all ids of a blob must have the same length
+            let block_id = base64::encode(format!("{:08}", i));
+            log::trace!("{} block {}", blob, i + 1);
+            self.send(
+                Method::PUT,
+                Some(blob),
+                &[("comp", "block"), ("blockid", &block_id)],
+                &[],
+                block,
+            )
+            .with_context(|| format!("Failed to upload block {}", i + 1))?;
+            block_ids.push(block_id);
+        }
+
+        let block_list = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+            block_ids
+                .iter()
+                .map(|id| format!("<Latest>{}</Latest>", id))
+                .collect::<String>()
+        );
+        self.send(
+            Method::PUT,
+            Some(blob),
+            &[("comp", "blocklist")],
+            &[("Content-Type", "application/xml")],
+            block_list.as_bytes(),
+        )
+        .with_context(|| "Failed to commit blocks")
+        .map(|response| Self::etag(&response))
+    }
+
+    fn head(&self, blob: &str) -> Result<Option<Head>> {
+        let response = self.request(Method::HEAD, Some(blob), &[], &[], &[])?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.is_success() {
+            // a HEAD response has no body, hence no error code
+            bail!("Azure: error {}", response.status());
+        }
+        Ok(Some(Head {
+            size: response
+                .headers()
+                .get("Content-Length")
+                .and_then(|size| size.to_str().ok())
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(|| anyhow!("Azure: no Content-Length"))?,
+            sha256: None,
+            etag: Self::etag(&response),
+            retain_until: None,
+        }))
+    }
+
+    fn etag(response: &Response) -> Option<String> {
+        response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut marker = String::new();
+        loop {
+            let mut query = vec![
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", prefix),
+            ];
+            if !marker.is_empty() {
+                query.push(("marker", &marker));
+            }
+            let xml = self
+                .send(Method::GET, None, &query, &[], &[])?
+                .text_utf8()?;
+            let (page, next_marker) = parse_list(&xml)?;
+            names.extend(page);
+            match next_marker {
+                Some(next_marker) => marker = next_marker,
+                None => return Ok(names),
+            }
+        }
+    }
+}
+
+/// The string signed with the shared key.
+/// source: https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+fn string_to_sign(
+    method: &str,
+    account: &str,
+    path: &str,
+    query: &[(&str, &str)],
+    ms_headers: &[(&str, &str)],
+    content_length: usize,
+    content_type: &str,
+) -> String {
+    let mut ms_headers = ms_headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim()))
+        .collect::<Vec<(String, &str)>>();
+    ms_headers.sort();
+    let mut query = query
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), *v))
+        .collect::<Vec<(String, &str)>>();
+    query.sort();
+
+    format!(
+        "{}\n\n\n{}\n\n{}\n\n\n\n\n\n\n{}/{}{}{}",
+        method,
+        if content_length > 0 {
+            content_length.to_string()
+        } else {
+            String::new()
+        },
+        content_type,
+        ms_headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v))
+            .collect::<String>(),
+        account,
+        path,
+        query
+            .iter()
+            .map(|(k, v)| format!("\n{}:{}", k, v))
+            .collect::<String>()
+    )
+}
+
+/// Extracts the blob names and the next marker from a List Blobs response.
+fn parse_list(xml: &str) -> Result<(Vec<String>, Option<String>)> {
+    let mut names = Vec::new();
+    let mut next_marker = None;
+    let mut element = String::new();
+    for token in Tokenizer::from(xml) {
+        match token.map_err(|e| anyhow!("Invalid List Blobs response: {}", e))? {
+            XmlToken::ElementStart { local, .. } => element = local.as_str().to_string(),
+            XmlToken::ElementEnd {
+                end: ElementEnd::Open,
+                ..
+            } => {}
+            XmlToken::ElementEnd { .. } => element.clear(),
+            XmlToken::Text { text } => match element.as_str() {
+                "Name" => names.push(text.as_str().to_string()),
+                "NextMarker" if !text.as_str().is_empty() => {
+                    next_marker = Some(text.as_str().to_string())
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok((names, next_marker))
+}
+
+#[async_trait]
+impl Store for AzureBlob {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("{}: start upload", object_id);
+        let blob = self.path(object_id);
+        let data = Vec::from(data);
+        let block_size = self.block_size;
+        let etag = self
+            .run(move |client| {
+                if block_size > 0 && data.len() > block_size {
+                    client.put_blocks(&blob, &data, block_size)
+                } else {
+                    client.put_blob(&blob, &data)
+                }
+            })
+            .await
+            .with_context(|| "Failed to upload")?;
+        log::debug!("{}: upload completed", object_id);
+        Ok(Stored {
+            etag,
+            ..Default::default()
+        })
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let blob = self.path(object_id);
+        let data = self
+            .run(move |client| {
+                Ok(client
+                    .send(Method::GET, Some(&blob), &[], &[], &[])?
+                    .bytes()?)
+            })
+            .await
+            .with_context(|| "Failed to download")?;
+        log::debug!("{}: download completed", object_id);
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let blob = self.path(object_id);
+        self.run(move |client| client.head(&blob))
+            .await
+            .with_context(|| "Failed to check object")
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        let prefix = self.prefix.clone();
+        let names = self
+            .run(move |client| client.list(&prefix))
+            .await
+            .with_context(|| "Failed to list objects")?;
+        Ok(names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&self.prefix))
+            .filter_map(|name| Uuid::parse_str(name).ok())
+            .collect())
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        let blob = self.path(object_id);
+        self.run(move |client| {
+            client
+                .send(Method::DELETE, Some(&blob), &[], &[], &[])
+                .map(|_| ())
+        })
+        .await
+        .with_context(|| "Failed to delete")
+    }
+}
+
+/// The round trips run against Azurite with `cargo test -- --ignored`, e.g. started with
+/// `docker run -p 10000:10000 mcr.microsoft.com/azure-storage/azurite azurite-blob
+/// --blobHost 0.0.0.0`; its endpoint is taken from `FS2CLOUD_TEST_AZURITE_ENDPOINT`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Azurite's well-known account key.
+    const AZURITE_KEY: &str =
+        "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    async fn azurite(block_size: u64) -> AzureBlob {
+        let endpoint = env::var("FS2CLOUD_TEST_AZURITE_ENDPOINT")
+            .unwrap_or_else(|_| "http://127.0.0.1:10000/devstoreaccount1".to_string());
+        let store = AzureBlob::new(Config {
+            account: "devstoreaccount1",
+            container: "fs2cloud",
+            endpoint: Some(&endpoint),
+            prefix: Some(&Uuid::new_v4().to_string()),
+            access_key: Some(AZURITE_KEY),
+            sas_token: None,
+            block_size,
+        })
+        .unwrap();
+        // fails if it already exists
+        let _ = store
+            .run(|client| {
+                client
+                    .send(Method::PUT, None, &[("restype", "container")], &[], &[])
+                    .map(|_| ())
+            })
+            .await;
+        store
+    }
+
+    async fn round_trip(store: AzureBlob, data: &[u8]) {
+        let object_id = Uuid::new_v4();
+
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        store.put(object_id, data).await.unwrap();
+
+        assert_eq!(store.get(object_id).await.unwrap(), data);
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        assert_eq!(
+            store.head(object_id).await.unwrap().unwrap().size,
+            data.len() as u64
+        );
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        assert!(store.get(object_id).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn blob_round_trip() {
+        round_trip(azurite(0).await, "hello".as_bytes()).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn blocks_round_trip() {
+        let data = (0..2500).map(|i| i as u8).collect::<Vec<u8>>();
+        round_trip(azurite(1024).await, &data).await;
+    }
+
+    #[test]
+    fn shared_key_string_to_sign() {
+        assert_eq!(
+            string_to_sign(
+                "PUT",
+                "devstoreaccount1",
+                "/devstoreaccount1/chunks/abcd",
+                &[("comp", "block"), ("blockid", "MDAwMDAwMDA=")],
+                &[
+                    ("x-ms-version", API_VERSION),
+                    ("x-ms-date", "Fri, 26 Jun 2015 23:39:12 GMT")
+                ],
+                11,
+                ""
+            ),
+            "PUT\n\n\n11\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Fri, 26 Jun 2015 23:39:12 GMT\n\
+             x-ms-version:2020-10-02\n\
+             /devstoreaccount1/devstoreaccount1/chunks/abcd\n\
+             blockid:MDAwMDAwMDA=\n\
+             comp:block"
+        );
+    }
+
+    #[test]
+    fn list_response() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="http://127.0.0.1:10000/devstoreaccount1" ContainerName="chunks">
+  <Prefix>backup/</Prefix>
+  <Blobs>
+    <Blob><Name>backup/abcdef01-2345-6789-abcd-ef0123456789</Name><Properties/></Blob>
+  </Blobs>
+  <NextMarker>page2</NextMarker>
+</EnumerationResults>"#;
+
+        assert_eq!(
+            parse_list(xml).unwrap(),
+            (
+                vec!["backup/abcdef01-2345-6789-abcd-ef0123456789".to_string()],
+                Some("page2".to_string())
+            )
+        );
+    }
+}
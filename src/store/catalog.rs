@@ -0,0 +1,101 @@
+use crate::chunk_location::repository::Repository as ChunkLocationsRepository;
+use crate::status::Status;
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Reads chunks from whichever store the catalog locates them in, preferring the default one.
+/// Writes go to the default store.
+pub struct Catalog {
+    default: String,
+    stores: HashMap<String, Box<dyn Store>>,
+    chunk_locations_repository: ChunkLocationsRepository,
+}
+
+impl Catalog {
+    pub fn new(
+        default: String,
+        stores: HashMap<String, Box<dyn Store>>,
+        chunk_locations_repository: ChunkLocationsRepository,
+    ) -> Self {
+        Self {
+            default,
+            stores,
+            chunk_locations_repository,
+        }
+    }
+
+    fn default_store(&self) -> &dyn Store {
+        self.stores
+            .get(&self.default)
+            .expect("default store is configured")
+            .as_ref()
+    }
+
+    /// The names of the stores holding the object, the default one first. Objects pushed before
+    /// their locations were recorded are in the default store.
+    fn locate(&self, object_id: Uuid) -> Result<Vec<String>> {
+        let mut names = self
+            .chunk_locations_repository
+            .find_by_chunk_uuid(&object_id)
+            .with_context(|| "Failed to load chunk locations")?
+            .into_iter()
+            .filter(|location| location.status == Status::Done)
+            .map(|location| location.store)
+            .collect::<Vec<String>>();
+        if names.is_empty() {
+            names.push(self.default.clone());
+        }
+        names.sort_by_key(|name| *name != self.default);
+        Ok(names)
+    }
+}
+
+#[async_trait]
+impl Store for Catalog {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        self.default_store().put(object_id, data).await
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        let mut error = None;
+        for name in self.locate(object_id)? {
+            let store = match self.stores.get(&name) {
+                Some(store) => store,
+                None => {
+                    log::warn!("{}: located in unknown store `{}`", object_id, name);
+                    continue;
+                }
+            };
+            match store
+                .get(object_id)
+                .await
+                .with_context(|| format!("Failed to read from store `{}`", name))
+            {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    log::debug!("{}: {:#}", object_id, e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| anyhow!("Object {} is in no configured store", object_id)))
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.default_store().head(object_id).await
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        self.default_store().delete(object_id).await
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        for store in self.stores.values() {
+            store.cleanup().await?;
+        }
+        Ok(())
+    }
+}
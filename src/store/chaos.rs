@@ -0,0 +1,115 @@
+use crate::store::{Head, Store, Stored};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub struct Config {
+    /// delay added to each operation
+    pub latency: Duration,
+    /// probability (from 0 to 1) of an operation to fail
+    pub error_rate: f64,
+    /// probability of an operation to be denied, as if the credentials were invalid
+    pub forbidden_rate: f64,
+    /// probability of a read to return only the beginning of the object
+    pub truncate_rate: f64,
+    /// probability of a read to return the object with a byte altered
+    pub corrupt_rate: f64,
+    /// seed of the faults, to replay a run; random if not set
+    pub seed: Option<u64>,
+}
+
+/// Wraps a store and injects faults in its operations.
+pub struct Chaos {
+    inner: Box<dyn Store>,
+    config: Config,
+    rng: Mutex<StdRng>,
+}
+
+enum Fault {
+    Error,
+    Forbidden,
+}
+
+impl Chaos {
+    pub fn new(inner: Box<dyn Store>, config: Config) -> Chaos {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        log::info!("Chaos: seed {}", seed);
+        Chaos {
+            inner,
+            config,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    async fn before(&self, operation: &str, object_id: Uuid) -> Result<()> {
+        if !self.config.latency.is_zero() {
+            tokio::time::sleep(self.config.latency).await;
+        }
+
+        let fault = {
+            let mut rng = self.rng.lock().unwrap();
+            if rng.gen_bool(self.config.forbidden_rate) {
+                Some(Fault::Forbidden)
+            } else if rng.gen_bool(self.config.error_rate) {
+                Some(Fault::Error)
+            } else {
+                None
+            }
+        };
+
+        match fault {
+            Some(Fault::Forbidden) => bail!("Chaos: {} {}: 403 Forbidden", operation, object_id),
+            Some(Fault::Error) => bail!("Chaos: {} {}: injected error", operation, object_id),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for Chaos {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        self.before("put", object_id).await?;
+        self.inner.put(object_id, data).await
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        self.before("get", object_id).await?;
+        let mut data = self.inner.get(object_id).await?;
+
+        let mut rng = self.rng.lock().unwrap();
+        if !data.is_empty() && rng.gen_bool(self.config.truncate_rate) {
+            let len = rng.gen_range(0..data.len());
+            log::debug!("Chaos: get {}: truncated to {} bytes", object_id, len);
+            data.truncate(len);
+        }
+        if !data.is_empty() && rng.gen_bool(self.config.corrupt_rate) {
+            let i = rng.gen_range(0..data.len());
+            log::debug!("Chaos: get {}: byte {} corrupted", object_id, i);
+            data[i] ^= rng.gen_range(1..=u8::MAX);
+        }
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.before("head", object_id).await?;
+        self.inner.head(object_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        self.before("list", Uuid::nil()).await?;
+        self.inner.list().await
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        self.before("delete", object_id).await?;
+        self.inner.delete(object_id).await
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        self.inner.cleanup().await
+    }
+}
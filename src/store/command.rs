@@ -0,0 +1,165 @@
+use crate::store::{Head, Store, Stored};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::process::{Output, Stdio};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command as Process;
+use uuid::Uuid;
+
+pub struct Config<'a> {
+    pub program: &'a str,
+    /// arguments passed to the program before the operation
+    pub args: Vec<&'a str>,
+}
+
+/// Delegates the operations to an external program, called as `<program> [args] <operation>
+/// [object id]`:
+///  - `put <object id>`: the object is sent on stdin;
+///  - `get <object id>`: the object is expected on stdout;
+///  - `head <object id>`: the size of the object is expected on stdout, nothing if there is no
+///    such object;
+///  - `list`: the object ids are expected on stdout, one per line;
+///  - `delete <object id>`.
+///
+/// A non-zero exit status means the operation failed.
+pub struct Command {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Command {
+    pub fn new(config: Config) -> Command {
+        Command {
+            program: config.program.to_string(),
+            args: config.args.into_iter().map(String::from).collect(),
+        }
+    }
+
+    fn process(&self, operation: &str, object_id: Option<Uuid>) -> Process {
+        let mut process = Process::new(&self.program);
+        process
+            .args(&self.args)
+            .arg(operation)
+            .args(object_id.map(|id| id.to_string()))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        process
+    }
+
+    fn check(operation: &str, output: Output) -> Result<Vec<u8>> {
+        if !output.status.success() {
+            bail!(
+                "`{}` failed ({}): {}",
+                operation,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    async fn run(&self, operation: &str, object_id: Option<Uuid>) -> Result<Vec<u8>> {
+        let output = self
+            .process(operation, object_id)
+            .output()
+            .await
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        Self::check(operation, output)
+    }
+}
+
+#[async_trait]
+impl Store for Command {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("{}: start upload", object_id);
+        let mut child = self
+            .process("put", Some(object_id))
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stdin.write_all(data).await;
+        drop(stdin);
+
+        let output = child
+            .wait_with_output()
+            .await
+            .with_context(|| format!("Failed to run {}", self.program))?;
+        Self::check("put", output)?;
+        written.with_context(|| "Failed to send data")?;
+
+        log::debug!("{}: upload completed", object_id);
+        Ok(Stored::default())
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let data = self.run("get", Some(object_id)).await?;
+        log::debug!("{}: download completed", object_id);
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let output = String::from_utf8(self.run("head", Some(object_id)).await?)
+            .with_context(|| "`head` returned invalid UTF-8")?;
+        match output.trim() {
+            "" => Ok(None),
+            size => Ok(Some(Head {
+                size: size
+                    .parse()
+                    .with_context(|| format!("`head` returned an invalid size: {}", size))?,
+                sha256: None,
+                etag: None,
+                retain_until: None,
+            })),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        String::from_utf8(self.run("list", None).await?)
+            .with_context(|| "`list` returned invalid UTF-8")?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                Uuid::parse_str(line)
+                    .with_context(|| format!("`list` returned an invalid object id: {}", line))
+            })
+            .collect()
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        self.run("delete", Some(object_id)).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn put_get_list_delete() {
+        let folder = std::env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()));
+        let store = Command::new(Config {
+            program: concat!(env!("CARGO_MANIFEST_DIR"), "/fake_store.sh"),
+            args: vec![folder.to_str().unwrap()],
+        });
+        let object_id = Uuid::new_v4();
+
+        store.put(object_id, "hello".as_bytes()).await.unwrap();
+        assert_eq!(store.get(object_id).await.unwrap(), "hello".as_bytes());
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        assert_eq!(store.head(object_id).await.unwrap().unwrap().size, 5);
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.get(object_id).await.is_err());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        assert!(store.list().await.unwrap().is_empty());
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,587 @@
+use crate::store::token::{self, Token};
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use attohttpc::{Method, RequestBuilder, Response, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub struct Config<'a> {
+    pub bucket: &'a str,
+    /// endpoint of the service, e.g. fake-gcs-server's; `https://storage.googleapis.com` if not set
+    pub endpoint: Option<&'a str>,
+    /// prefix of the objects names inside the bucket
+    pub prefix: Option<&'a str>,
+    /// service account or authorized user JSON file
+    pub credentials: Option<&'a str>,
+    /// chunks larger than this are uploaded in a resumable upload, in pieces of this size; 0 to
+    /// disable
+    pub resumable_chunk_size: u64,
+}
+
+/// Characters escaped in query values and object names, which are a single path segment.
+const ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Pieces of a resumable upload must be a multiple of this size, except for the last one
+/// source: https://cloud.google.com/storage/docs/performing-resumable-uploads
+const RESUMABLE_CHUNK_GRANULARITY: u64 = 256 * 1024;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+pub struct Gcs {
+    client: Arc<Client>,
+    prefix: String,
+    resumable_chunk_size: usize,
+}
+
+struct Client {
+    endpoint: String,
+    bucket: String,
+    credentials: Credentials,
+    token: Token,
+}
+
+enum Credentials {
+    ServiceAccount {
+        client_email: String,
+        key_pair: Box<RsaKeyPair>,
+        token_uri: String,
+    },
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    MetadataServer,
+    Anonymous,
+}
+
+impl Credentials {
+    /// Looks for a credentials file in the configuration, then in `GOOGLE_APPLICATION_CREDENTIALS`,
+    /// then the one of `gcloud auth application-default login`. Falls back to the metadata server
+    /// of the host, or to no authentication at all with a custom endpoint (emulators).
+    fn find(config: &Config) -> Result<Credentials> {
+        let file = config
+            .credentials
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                    .ok()
+                    .map(PathBuf::from)
+            })
+            .or_else(|| {
+                env::var("HOME")
+                    .ok()
+                    .map(|home| {
+                        PathBuf::from(home)
+                            .join(".config/gcloud/application_default_credentials.json")
+                    })
+                    .filter(|path| path.is_file())
+            });
+
+        match file {
+            Some(file) => Self::from_file(
+                &fs::read_to_string(&file)
+                    .with_context(|| format!("Failed to read {}", file.display()))?,
+            )
+            .with_context(|| format!("Invalid credentials in {}", file.display())),
+            None if config.endpoint.is_some() => Ok(Credentials::Anonymous),
+            None => Ok(Credentials::MetadataServer),
+        }
+    }
+
+    fn from_file(json: &str) -> Result<Credentials> {
+        let credentials: Value = serde_json::from_str(json)?;
+        let field = |name: &str| {
+            credentials[name]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| anyhow!("`{}` is missing", name))
+        };
+
+        match credentials["type"].as_str() {
+            Some("service_account") => {
+                let pem = field("private_key")?;
+                let der = base64::decode(
+                    pem.lines()
+                        .filter(|line| !line.starts_with("-----"))
+                        .collect::<String>(),
+                )?;
+                Ok(Credentials::ServiceAccount {
+                    client_email: field("client_email")?,
+                    key_pair: Box::new(
+                        RsaKeyPair::from_pkcs8(&der)
+                            .map_err(|e| anyhow!("Invalid private key: {}", e))?,
+                    ),
+                    token_uri: field("token_uri").unwrap_or_else(|_| TOKEN_URI.to_string()),
+                })
+            }
+            Some("authorized_user") => Ok(Credentials::AuthorizedUser {
+                client_id: field("client_id")?,
+                client_secret: field("client_secret")?,
+                refresh_token: field("refresh_token")?,
+            }),
+            t => bail!("Unsupported credentials type: {}", t.unwrap_or_default()),
+        }
+    }
+}
+
+impl Gcs {
+    pub fn new(config: Config) -> Result<Gcs> {
+        let chunk_size = config.resumable_chunk_size;
+        Ok(Gcs {
+            client: Arc::new(Client {
+                endpoint: config
+                    .endpoint
+                    .unwrap_or("https://storage.googleapis.com")
+                    .trim_end_matches('/')
+                    .to_string(),
+                bucket: config.bucket.to_string(),
+                credentials: Credentials::find(&config)?,
+                token: Token::default(),
+            }),
+            prefix: config
+                .prefix
+                .map(|p| p.trim_matches('/'))
+                .filter(|p| !p.is_empty())
+                .map(|p| format!("{}/", p))
+                .unwrap_or_default(),
+            resumable_chunk_size: (chunk_size - chunk_size % RESUMABLE_CHUNK_GRANULARITY) as usize,
+        })
+    }
+
+    fn path(&self, uuid: Uuid) -> String {
+        format!("{}{}", self.prefix, uuid)
+    }
+
+    /// Runs a blocking operation of the client without blocking the runtime.
+    async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&Client) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || operation(&client)).await?
+    }
+}
+
+impl Client {
+    fn request(&self, method: Method, url: String) -> Result<RequestBuilder> {
+        let request = RequestBuilder::new(method, url).follow_redirects(false);
+        Ok(match self.credentials {
+            Credentials::Anonymous => request,
+            _ => request.header(
+                "Authorization",
+                format!("Bearer {}", self.token.get(|| self.fetch_token())?),
+            ),
+        })
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.endpoint,
+            utf8_percent_encode(&self.bucket, ENCODE),
+            utf8_percent_encode(name, ENCODE)
+        )
+    }
+
+    fn upload_url(&self, upload_type: &str, name: &str) -> String {
+        format!(
+            "{}/upload/storage/v1/b/{}/o?uploadType={}&name={}",
+            self.endpoint,
+            utf8_percent_encode(&self.bucket, ENCODE),
+            upload_type,
+            utf8_percent_encode(name, ENCODE)
+        )
+    }
+
+    fn check(response: Response) -> Result<Response> {
+        if !response.is_success() {
+            let status = response.status();
+            bail!(
+                "GCS: error {}: {}",
+                status,
+                response.text_utf8().unwrap_or_default().trim()
+            );
+        }
+        Ok(response)
+    }
+
+    fn fetch_token(&self) -> Result<(String, Duration)> {
+        let response = match &self.credentials {
+            Credentials::ServiceAccount {
+                client_email,
+                key_pair,
+                token_uri,
+            } => RequestBuilder::new(Method::POST, token_uri)
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .text(format!(
+                    "grant_type={}&assertion={}",
+                    utf8_percent_encode("urn:ietf:params:oauth:grant-type:jwt-bearer", ENCODE),
+                    jwt(client_email, key_pair, token_uri)?
+                ))
+                .send()?,
+            Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => RequestBuilder::new(Method::POST, TOKEN_URI)
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .text(format!(
+                    "grant_type=refresh_token&client_id={}&client_secret={}&refresh_token={}",
+                    utf8_percent_encode(client_id, ENCODE),
+                    utf8_percent_encode(client_secret, ENCODE),
+                    utf8_percent_encode(refresh_token, ENCODE)
+                ))
+                .send()?,
+            _ => RequestBuilder::new(
+                Method::GET,
+                "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token",
+            )
+            .header("Metadata-Flavor", "Google")
+            .send()?,
+        };
+        token::parse_response(&Self::check(response)?.text_utf8()?)
+            .with_context(|| "Failed to get access token")
+    }
+
+    fn upload(&self, name: &str, data: &[u8]) -> Result<Option<String>> {
+        Self::check(
+            self.request(Method::POST, self.upload_url("media", name))?
+                .header("Content-Type", "application/octet-stream")
+                .bytes(data)
+                .send()?,
+        )
+        .map(|response| Self::etag(&response))
+    }
+
+    /// Reads the object's metadata, in which the size is a string.
+    fn head(&self, name: &str) -> Result<Option<Head>> {
+        let url = format!("{}?fields=size,etag", self.object_url(name));
+        let response = self.request(Method::GET, url)?.send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let metadata: Value = serde_json::from_str(&Self::check(response)?.text_utf8()?)?;
+        Ok(Some(Head {
+            size: metadata["size"]
+                .as_str()
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(|| anyhow!("GCS: no size in metadata"))?,
+            sha256: None,
+            etag: metadata["etag"].as_str().map(String::from),
+            retain_until: None,
+        }))
+    }
+
+    fn etag(response: &Response) -> Option<String> {
+        response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from)
+    }
+
+    /// Uploads the data in pieces of `chunk_size` bytes, in a single resumable upload session.
+    fn resumable_upload(
+        &self,
+        name: &str,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<Option<String>> {
+        let session = Self::check(
+            self.request(Method::POST, self.upload_url("resumable", name))?
+                .header("X-Upload-Content-Type", "application/octet-stream")
+                .header("X-Upload-Content-Length", data.len())
+                .send()?,
+        )
+        .with_context(|| "Failed to start resumable upload")?;
+        let session_url = session
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow!("Failed to start resumable upload: no session URL"))?
+            .to_string();
+
+        let mut etag = None;
+        for (i, piece) in data.chunks(chunk_size).enumerate() {
+            let from = i * chunk_size;
+            log::trace!("{} piece {} ({} bytes)", name, i + 1, piece.len());
+            let response = self
+                .request(Method::PUT, session_url.clone())?
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", from, from + piece.len() - 1, data.len()),
+                )
+                .bytes(piece)
+                .send()?;
+            // 308 means the upload is incomplete
+            if response.status() != StatusCode::PERMANENT_REDIRECT {
+                let response = Self::check(response)
+                    .with_context(|| format!("Failed to upload piece {}", i + 1))?;
+                etag = Self::etag(&response);
+            }
+        }
+        Ok(etag)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "{}/storage/v1/b/{}/o?prefix={}&fields=items(name),nextPageToken",
+                self.endpoint,
+                utf8_percent_encode(&self.bucket, ENCODE),
+                utf8_percent_encode(prefix, ENCODE)
+            );
+            if let Some(page_token) = &page_token {
+                url = format!(
+                    "{}&pageToken={}",
+                    url,
+                    utf8_percent_encode(page_token, ENCODE)
+                );
+            }
+            let page: Value = serde_json::from_str(
+                &Self::check(self.request(Method::GET, url)?.send()?)?.text_utf8()?,
+            )?;
+
+            names.extend(
+                page["items"]
+                    .as_array()
+                    .map(|items| items.as_slice())
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|item| item["name"].as_str())
+                    .map(String::from),
+            );
+            match page["nextPageToken"].as_str() {
+                Some(token) => page_token = Some(token.to_string()),
+                None => return Ok(names),
+            }
+        }
+    }
+}
+
+/// A JWT signed by the service account, to exchange for an access token.
+/// source: https://developers.google.com/identity/protocols/oauth2/service-account#authorizingrequests
+fn jwt(client_email: &str, key_pair: &RsaKeyPair, token_uri: &str) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let encode = |json: Value| base64::encode_config(json.to_string(), base64::URL_SAFE_NO_PAD);
+    let message = format!(
+        "{}.{}",
+        encode(json!({"alg": "RS256", "typ": "JWT"})),
+        encode(json!({
+            "iss": client_email,
+            "scope": SCOPE,
+            "aud": token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        }))
+    );
+
+    let mut signature = vec![0; key_pair.public_modulus_len()];
+    key_pair
+        .sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|_| anyhow!("Failed to sign token request"))?;
+    Ok(format!(
+        "{}.{}",
+        message,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+#[async_trait]
+impl Store for Gcs {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("{}: start upload", object_id);
+        let name = self.path(object_id);
+        let data = Vec::from(data);
+        let chunk_size = self.resumable_chunk_size;
+        let etag = self
+            .run(move |client| {
+                if chunk_size > 0 && data.len() > chunk_size {
+                    client.resumable_upload(&name, &data, chunk_size)
+                } else {
+                    client.upload(&name, &data)
+                }
+            })
+            .await
+            .with_context(|| "Failed to upload")?;
+        log::debug!("{}: upload completed", object_id);
+        Ok(Stored {
+            etag,
+            ..Default::default()
+        })
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let name = self.path(object_id);
+        let data = self
+            .run(move |client| {
+                let url = format!("{}?alt=media", client.object_url(&name));
+                Ok(Client::check(client.request(Method::GET, url)?.send()?)?.bytes()?)
+            })
+            .await
+            .with_context(|| "Failed to download")?;
+        log::debug!("{}: download completed", object_id);
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let name = self.path(object_id);
+        self.run(move |client| client.head(&name))
+            .await
+            .with_context(|| "Failed to check object")
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        let prefix = self.prefix.clone();
+        let names = self
+            .run(move |client| client.list(&prefix))
+            .await
+            .with_context(|| "Failed to list objects")?;
+        Ok(names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&self.prefix))
+            .filter_map(|name| Uuid::parse_str(name).ok())
+            .collect())
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        let name = self.path(object_id);
+        self.run(move |client| {
+            let url = client.object_url(&name);
+            Client::check(client.request(Method::DELETE, url)?.send()?).map(|_| ())
+        })
+        .await
+        .with_context(|| "Failed to delete")
+    }
+}
+
+/// The round trips run against fake-gcs-server with `cargo test -- --ignored`, e.g. started with
+/// `docker run -p 4443:4443 fsouza/fake-gcs-server -scheme http -public-host localhost:4443`; its
+/// endpoint is taken from `FS2CLOUD_TEST_GCS_ENDPOINT`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fake_gcs_server(resumable_chunk_size: u64) -> Gcs {
+        let endpoint = env::var("FS2CLOUD_TEST_GCS_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4443".to_string());
+        let store = Gcs::new(Config {
+            bucket: "fs2cloud",
+            endpoint: Some(&endpoint),
+            prefix: Some(&Uuid::new_v4().to_string()),
+            credentials: None,
+            resumable_chunk_size,
+        })
+        .unwrap();
+        // fails if it already exists
+        let _ = store
+            .run(|client| {
+                client
+                    .request(Method::POST, format!("{}/storage/v1/b", client.endpoint))?
+                    .header("Content-Type", "application/json")
+                    .text(json!({ "name": client.bucket }).to_string())
+                    .send()
+                    .map(|_| ())
+                    .map_err(Into::into)
+            })
+            .await;
+        store
+    }
+
+    async fn round_trip(store: Gcs, data: &[u8]) {
+        let object_id = Uuid::new_v4();
+
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        store.put(object_id, data).await.unwrap();
+
+        assert_eq!(store.get(object_id).await.unwrap(), data);
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        assert_eq!(
+            store.head(object_id).await.unwrap().unwrap().size,
+            data.len() as u64
+        );
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        assert!(store.get(object_id).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn upload_round_trip() {
+        round_trip(fake_gcs_server(0).await, "hello".as_bytes()).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn resumable_upload_round_trip() {
+        let data = (0..2 * RESUMABLE_CHUNK_GRANULARITY + 1000)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        round_trip(fake_gcs_server(RESUMABLE_CHUNK_GRANULARITY).await, &data).await;
+    }
+
+    #[test]
+    fn authorized_user_credentials() {
+        match Credentials::from_file(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"token"}"#,
+        )
+        .unwrap()
+        {
+            Credentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => {
+                assert_eq!(client_id, "id");
+                assert_eq!(client_secret, "secret");
+                assert_eq!(refresh_token, "token");
+            }
+            _ => panic!("authorized user expected"),
+        }
+        assert!(Credentials::from_file(r#"{"type":"external_account"}"#).is_err());
+    }
+
+    #[test]
+    fn resumable_chunk_size_granularity() {
+        let gcs = Gcs::new(Config {
+            bucket: "bucket",
+            endpoint: Some("http://localhost:4443"),
+            prefix: Some("/backup/"),
+            credentials: None,
+            resumable_chunk_size: 1_000_000,
+        })
+        .unwrap();
+        assert_eq!(gcs.resumable_chunk_size, 786_432);
+        assert_eq!(
+            gcs.client.object_url(&gcs.path(Uuid::nil())),
+            "http://localhost:4443/storage/v1/b/bucket/o/backup%2F00000000-0000-0000-0000-000000000000"
+        );
+    }
+}
@@ -1,12 +1,20 @@
-use crate::store::Store;
-use anyhow::Result;
+use crate::store::{Head, Store, Stored};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use sha2::{Digest, Sha256};
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
+/// Objects are sharded in two levels of subdirectories, named after the first 4 hex digits of
+/// their UUID: `<path>/ab/cd/abcd...`.
+pub fn shards(object_id: Uuid) -> (String, String) {
+    let object_id = object_id.to_string();
+    (object_id[0..2].to_string(), object_id[2..4].to_string())
+}
+
 pub struct Local {
     path: PathBuf,
 }
@@ -14,28 +22,84 @@ pub struct Local {
 impl Local {
     pub fn new(path: &str) -> Result<Self> {
         fs::create_dir_all(path)?;
-        Ok(Self {
+        let local = Self {
             path: PathBuf::from(path),
-        })
+        };
+        local
+            .migrate()
+            .with_context(|| format!("Failed to migrate {} to sharded layout", path))?;
+        Ok(local)
+    }
+
+    fn object_path(&self, object_id: Uuid) -> PathBuf {
+        let (shard, sub_shard) = shards(object_id);
+        let mut path = PathBuf::from(self.path.as_path());
+        path.push(shard);
+        path.push(sub_shard);
+        path.push(object_id.to_string());
+        path
+    }
+
+    /// Moves the objects stored directly in `<path>` (flat layout) to their sharded location.
+    fn migrate(&self) -> Result<()> {
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let object_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok().filter(|u| u.to_string() == name))
+            {
+                Some(object_id) => object_id,
+                None => continue,
+            };
+
+            let path = self.object_path(object_id);
+            log::info!(
+                "Moving chunk {} from {} to {}",
+                object_id,
+                entry.path().display(),
+                path.display()
+            );
+            fs::create_dir_all(path.parent().expect("object path has a parent"))?;
+            fs::rename(entry.path(), &path)?;
+        }
+        Ok(())
+    }
+
+    fn sync_dir(path: &Path) -> Result<()> {
+        File::open(path)?.sync_all()?;
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Store for Local {
-    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<()> {
-        let mut path = PathBuf::from(self.path.as_path());
-        path.push(object_id.to_string());
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        let path = self.object_path(object_id);
+        let dir = path.parent().expect("object path has a parent");
+        let tmp_path = dir.join(format!(".{}.tmp", object_id));
 
         log::debug!("Writing chunk {} to {}", object_id, path.display());
 
-        let mut file = OpenOptions::new().create(true).write(true).open(path)?;
+        fs::create_dir_all(dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
         file.write_all(data)?;
-        Ok(())
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Self::sync_dir(dir)?;
+        Ok(Stored::default())
     }
 
     async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
-        let mut path = PathBuf::from(self.path.as_path());
-        path.push(object_id.to_string());
+        let path = self.object_path(object_id);
 
         log::debug!("Reading chunk {} from {}", object_id, path.display());
 
@@ -45,4 +109,116 @@ impl Store for Local {
 
         Ok(bytes)
     }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let mut file = match File::open(self.object_path(object_id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)?;
+        Ok(Some(Head {
+            size,
+            sha256: Some(format!("{:x}", hasher.finalize())),
+            etag: None,
+            retain_until: None,
+        }))
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        let mut objects = Vec::new();
+        for shard in fs::read_dir(&self.path)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for sub_shard in fs::read_dir(shard.path())? {
+                let sub_shard = sub_shard?;
+                if !sub_shard.file_type()?.is_dir() {
+                    continue;
+                }
+                for entry in fs::read_dir(sub_shard.path())? {
+                    if let Some(object_id) = entry?
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| Uuid::parse_str(name).ok())
+                    {
+                        objects.push(object_id);
+                    }
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        let path = self.object_path(object_id);
+
+        log::debug!("Deleting chunk {} from {}", object_id, path.display());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn put_get() {
+        let root = temp_dir();
+        let store = Local::new(root.to_str().unwrap()).unwrap();
+        let object_id = Uuid::parse_str("abcdef01-2345-6789-abcd-ef0123456789").unwrap();
+
+        store.put(object_id, "hello".as_bytes()).await.unwrap();
+        store.put(object_id, "world".as_bytes()).await.unwrap();
+
+        assert!(root
+            .join("ab")
+            .join("cd")
+            .join("abcdef01-2345-6789-abcd-ef0123456789")
+            .is_file());
+        assert!(!root
+            .join("ab")
+            .join("cd")
+            .join(".abcdef01-2345-6789-abcd-ef0123456789.tmp")
+            .exists());
+        assert_eq!(store.get(object_id).await.unwrap(), "world".as_bytes());
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        let head = store.head(object_id).await.unwrap().unwrap();
+        assert_eq!(head.size, 5);
+        assert_eq!(
+            head.sha256.unwrap(),
+            "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7"
+        );
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate_flat_layout() {
+        let root = temp_dir();
+        fs::create_dir_all(&root).unwrap();
+        let object_id = Uuid::new_v4();
+        fs::write(root.join(object_id.to_string()), "hello").unwrap();
+        fs::write(root.join("not-a-chunk"), "world").unwrap();
+
+        let store = Local::new(root.to_str().unwrap()).unwrap();
+
+        assert!(!root.join(object_id.to_string()).exists());
+        assert!(root.join("not-a-chunk").exists());
+        assert_eq!(store.get(object_id).await.unwrap(), "hello".as_bytes());
+
+        fs::remove_dir_all(root).unwrap();
+    }
 }
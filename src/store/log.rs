@@ -1,4 +1,4 @@
-use crate::store::Store;
+use crate::store::{Store, Stored};
 use anyhow::Result;
 use async_trait::async_trait;
 use uuid::Uuid;
@@ -13,9 +13,9 @@ impl Log {
 
 #[async_trait]
 impl Store for Log {
-    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<()> {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
         log::info!("WRITE {} ({} bytes)", object_id, data.len());
-        Ok(())
+        Ok(Stored::default())
     }
 
     async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
@@ -0,0 +1,66 @@
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Keeps the objects in memory; they are lost when the process exits. Clones share the same
+/// objects.
+#[derive(Clone, Default)]
+pub struct Memory {
+    objects: Arc<Mutex<HashMap<Uuid, Vec<u8>>>>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory::default()
+    }
+}
+
+#[async_trait]
+impl Store for Memory {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("Writing chunk {} to memory", object_id);
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(object_id, Vec::from(data));
+        Ok(Stored::default())
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("Reading chunk {} from memory", object_id);
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&object_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Object {} not found", object_id))
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(&object_id)
+            .map(|data| Head {
+                size: data.len() as u64,
+                sha256: Some(format!("{:x}", sha2::Sha256::digest(data))),
+                etag: None,
+                retain_until: None,
+            }))
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        Ok(self.objects.lock().unwrap().keys().copied().collect())
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        log::debug!("Deleting chunk {} from memory", object_id);
+        self.objects.lock().unwrap().remove(&object_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use crate::chunk_location::repository::Repository as ChunkLocationsRepository;
+use crate::store::{Head, Store, Stored};
+use anyhow::{Context, Error, Result};
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter};
+use uuid::Uuid;
+
+/// Refuses to delete the objects the catalog records as retained in the wrapped store, whatever
+/// the caller, so that nothing deletes a chunk under Object Lock retention.
+pub struct Retention {
+    inner: Box<dyn Store>,
+    /// name of the wrapped store, under which the chunk locations are recorded
+    store: String,
+    chunk_locations_repository: ChunkLocationsRepository,
+}
+
+/// Returned by [`Retention::delete`] when the object is still retained.
+#[derive(Debug)]
+pub struct Retained {
+    pub object_id: Uuid,
+    /// in seconds since epoch
+    pub until: u64,
+}
+
+impl Display for Retained {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "object {} is retained until {}",
+            self.object_id, self.until
+        )
+    }
+}
+
+impl std::error::Error for Retained {}
+
+impl Retained {
+    pub fn is(error: &Error) -> bool {
+        error.chain().any(|e| e.is::<Retained>())
+    }
+}
+
+impl Retention {
+    pub fn new(
+        inner: Box<dyn Store>,
+        store: String,
+        chunk_locations_repository: ChunkLocationsRepository,
+    ) -> Retention {
+        Retention {
+            inner,
+            store,
+            chunk_locations_repository,
+        }
+    }
+}
+
+#[async_trait]
+impl Store for Retention {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        self.inner.put(object_id, data).await
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        self.inner.get(object_id).await
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.inner.head(object_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        self.inner.list().await
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        if let Some(until) = self
+            .chunk_locations_repository
+            .find_by_chunk_uuid_and_store(&object_id, &self.store)
+            .with_context(|| "Failed to load chunk location")?
+            .filter(|location| location.is_retained())
+            .and_then(|location| location.retain_until)
+        {
+            return Err(Retained { object_id, until }.into());
+        }
+        self.inner.delete(object_id).await
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        self.inner.cleanup().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_location::repository::ChunkLocation;
+    use crate::database;
+    use crate::status::Status;
+    use crate::store::memory::Memory;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn retained_objects_are_not_deleted() {
+        let folder = std::env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let sqlite = database::open(folder.join("db.sqlite").to_str().unwrap()).unwrap();
+        let memory = Memory::new();
+        let store = Retention::new(
+            Box::new(memory.clone()),
+            "default".to_string(),
+            ChunkLocationsRepository::new(sqlite.clone()),
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut objects = Vec::new();
+        for retain_until in [Some(now + 3600), Some(now - 3600), None] {
+            let object_id = Uuid::new_v4();
+            store.put(object_id, "hello".as_bytes()).await.unwrap();
+            ChunkLocationsRepository::new(sqlite.clone())
+                .insert(&ChunkLocation {
+                    chunk_uuid: object_id,
+                    store: "default".to_string(),
+                    status: Status::Done,
+                    size: 5,
+                    etag: None,
+                    retain_until,
+                    sha256: None,
+                })
+                .unwrap();
+            objects.push(object_id);
+        }
+
+        let error = store.delete(objects[0]).await.unwrap_err();
+        assert!(Retained::is(&error));
+        assert!(memory.head(objects[0]).await.unwrap().is_some());
+
+        store.delete(objects[1]).await.unwrap();
+        store.delete(objects[2]).await.unwrap();
+        assert_eq!(memory.list().await.unwrap(), vec![objects[0]]);
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -1,47 +1,154 @@
-use crate::store::Store;
+use crate::store::{Head, Store, Stored};
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use awscreds::Credentials;
-use s3::Bucket;
+use s3::{Bucket, Region};
 use uuid::Uuid;
 
+pub struct Config<'a> {
+    pub region: &'a str,
+    pub bucket: &'a str,
+    // todo should not be Option
+    pub access_key: Option<&'a str>,
+    pub secret_key: Option<&'a str>,
+    /// endpoint of an S3-compatible service (MinIO, Ceph, ...); AWS if not set
+    pub endpoint: Option<&'a str>,
+    pub path_style: bool,
+    /// prefix of the objects keys inside the bucket
+    pub prefix: Option<&'a str>,
+    /// server-side encryption: `AES256` or `aws:kms`
+    pub sse: Option<&'a str>,
+    pub sse_kms_key_id: Option<&'a str>,
+}
+
 pub struct S3 {
     bucket: Bucket,
+    prefix: String,
 }
 
 impl S3 {
-    // todo should not take Option as parameter
-    pub fn new(region: &str, bucket: &str, key: Option<&str>, secret: Option<&str>) -> Result<S3> {
+    pub fn new(config: Config) -> Result<S3> {
+        let region = match config.endpoint {
+            None => config.region.parse()?,
+            Some(endpoint) => Region::Custom {
+                region: config.region.to_string(),
+                endpoint: endpoint.to_string(),
+            },
+        };
+
+        let mut bucket = Bucket::new(
+            config.bucket,
+            region,
+            Credentials::new(config.access_key, config.secret_key, None, None, None)?,
+        )?;
+        if config.path_style {
+            bucket.set_path_style();
+        }
+
+        match (config.sse, config.sse_kms_key_id) {
+            (None, None) => {}
+            (Some(sse @ ("AES256" | "aws:kms")), None) => {
+                bucket.add_header("x-amz-server-side-encryption", sse)
+            }
+            (Some("aws:kms"), Some(key_id)) => {
+                bucket.add_header("x-amz-server-side-encryption", "aws:kms");
+                bucket.add_header("x-amz-server-side-encryption-aws-kms-key-id", key_id);
+            }
+            (_, Some(_)) => bail!("S3: a KMS key requires `aws:kms` server-side encryption"),
+            (Some(sse), _) => bail!("S3: invalid server-side encryption {}", sse),
+        }
+
         Ok(S3 {
-            bucket: Bucket::new(
-                bucket,
-                region.parse()?,
-                Credentials::new(key, secret, None, None, None)?,
-            )?,
+            bucket,
+            prefix: config
+                .prefix
+                .map(|p| p.trim_matches('/'))
+                .filter(|p| !p.is_empty())
+                .map(|p| format!("{}/", p))
+                .unwrap_or_default(),
         })
     }
 
-    fn path(uuid: Uuid) -> String {
-        format!("/{}", uuid)
+    fn path(&self, uuid: Uuid) -> String {
+        format!("/{}{}", self.prefix, uuid)
     }
 }
 
 #[async_trait]
 impl Store for S3 {
-    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<()> {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
         log::debug!("{}: start upload", object_id);
-        let (_, code) = self.bucket.put_object(Self::path(object_id), data)?;
+        let (_, code) = self.bucket.put_object(self.path(object_id), data)?;
         match code {
             200 => {
                 log::debug!("{}: upload completed", object_id);
-                Ok(())
+                Ok(Stored::default())
+            }
+            403 => bail!("S3: invalid credentials"),
+            _ => bail!("S3: error"),
+        }
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let (data, code) = self.bucket.get_object(self.path(object_id))?;
+        match code {
+            200 => {
+                log::debug!("{}: download completed", object_id);
+                Ok(data)
             }
             403 => bail!("S3: invalid credentials"),
+            404 => bail!("S3: object {} not found", object_id),
+            _ => bail!("S3: error"),
+        }
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let (head, code) = self.bucket.head_object(self.path(object_id))?;
+        match code {
+            200 => Ok(Some(Head {
+                size: head.content_length.unwrap_or_default() as u64,
+                sha256: None,
+                etag: head.e_tag,
+                retain_until: None,
+            })),
+            403 => bail!("S3: invalid credentials"),
+            404 => Ok(None),
             _ => bail!("S3: error"),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s3(sse: Option<&str>, sse_kms_key_id: Option<&str>) -> Result<S3> {
+        S3::new(Config {
+            region: "eu-west-1",
+            bucket: "bucket",
+            access_key: Some("access"),
+            secret_key: Some("secret"),
+            endpoint: None,
+            path_style: false,
+            prefix: None,
+            sse,
+            sse_kms_key_id,
+        })
+    }
 
-    async fn get(&self, _object_id: Uuid) -> Result<Vec<u8>> {
-        todo!()
+    #[test]
+    fn server_side_encryption() {
+        assert!(s3(None, None).is_ok());
+        assert!(s3(Some("AES256"), None).is_ok());
+        assert!(s3(Some("aws:kms"), Some("key")).is_ok());
+        assert_eq!(
+            s3(Some("AES256"), Some("key")).err().unwrap().to_string(),
+            "S3: a KMS key requires `aws:kms` server-side encryption"
+        );
+        assert_eq!(
+            s3(Some("DES"), None).err().unwrap().to_string(),
+            "S3: invalid server-side encryption DES"
+        );
     }
 }
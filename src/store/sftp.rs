@@ -0,0 +1,322 @@
+use crate::store::local::shards;
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+
+pub struct Config<'a> {
+    pub host: &'a str,
+    pub port: Option<u16>,
+    pub username: Option<&'a str>,
+    /// private key to authenticate with; ssh-agent and the default keys are used if not set
+    pub key_file: Option<&'a str>,
+    /// folder where the objects are stored on the remote host
+    pub path: &'a str,
+}
+
+/// Stores the objects on a remote host with the OpenSSH `sftp` client, sharded the same way as
+/// the [`Local`](crate::store::local::Local) store. The host key must already be known.
+pub struct Sftp {
+    destination: String,
+    args: Vec<String>,
+    path: String,
+}
+
+impl Sftp {
+    pub fn new(config: Config) -> Sftp {
+        let mut args = vec![
+            "-b".to_string(),
+            "-".to_string(),
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+        ];
+        if let Some(port) = config.port {
+            args.push("-P".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(key_file) = config.key_file {
+            args.push("-i".to_string());
+            args.push(key_file.to_string());
+        }
+
+        Sftp {
+            destination: match config.username {
+                Some(username) => format!("{}@{}", username, config.host),
+                None => config.host.to_string(),
+            },
+            args,
+            path: config.path.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The remote folder of an object.
+    fn object_dir(&self, object_id: Uuid) -> String {
+        let (shard, sub_shard) = shards(object_id);
+        format!("{}/{}/{}", self.path, shard, sub_shard)
+    }
+
+    fn local_tmp_path(object_id: Uuid) -> PathBuf {
+        std::env::temp_dir().join(format!("fs2cloud-sftp-{}-{}", object_id, Uuid::new_v4()))
+    }
+
+    /// Runs the batch of sftp commands and returns the output. The batch stops at the first
+    /// failing command, unless prefixed with `-`.
+    async fn batch(&self, commands: &str) -> Result<String> {
+        log::trace!("sftp {}:\n{}", self.destination, commands);
+        let mut child = Command::new("sftp")
+            .args(&self.args)
+            .arg(&self.destination)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| "Failed to run sftp")?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stdin.write_all(commands.as_bytes()).await;
+        drop(stdin);
+
+        let output = child
+            .wait_with_output()
+            .await
+            .with_context(|| "Failed to run sftp")?;
+        if !output.status.success() {
+            bail!(
+                "sftp failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        written.with_context(|| "Failed to send commands to sftp")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    async fn upload(&self, object_id: Uuid, local: &Path) -> Result<()> {
+        let (shard, _) = shards(object_id);
+        let dir = self.object_dir(object_id);
+        self.batch(&format!(
+            "-mkdir \"{path}\"\n\
+             -mkdir \"{path}/{shard}\"\n\
+             -mkdir \"{dir}\"\n\
+             put \"{local}\" \"{dir}/.{id}.tmp\"\n\
+             rename \"{dir}/.{id}.tmp\" \"{dir}/{id}\"\n",
+            path = self.path,
+            shard = shard,
+            dir = dir,
+            local = local.display(),
+            id = object_id
+        ))
+        .await
+        .map(|_| ())
+    }
+
+    async fn download(&self, object_id: Uuid, local: &Path) -> Result<Vec<u8>> {
+        self.batch(&format!(
+            "get \"{}/{}\" \"{}\"\n",
+            self.object_dir(object_id),
+            object_id,
+            local.display()
+        ))
+        .await?;
+        Ok(fs::read(local).await?)
+    }
+}
+
+/// Extracts the object ids from the output of `ls -1`, which also echoes the commands.
+fn parse_ls(output: &str) -> Vec<Uuid> {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("sftp>"))
+        .filter_map(|line| {
+            line.trim()
+                .rsplit('/')
+                .next()
+                .and_then(|name| Uuid::parse_str(name).ok())
+        })
+        .collect()
+}
+
+/// Extracts the size of the object from the output of `ls -ln`, which also echoes the commands;
+/// `None` if the object is not listed.
+fn parse_ls_long(output: &str, object_id: Uuid) -> Result<Option<u64>> {
+    let name = object_id.to_string();
+    output
+        .lines()
+        .filter(|line| !line.starts_with("sftp>"))
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| {
+            fields.last().and_then(|path| path.rsplit('/').next()) == Some(name.as_str())
+        })
+        .map(|fields| {
+            fields
+                .get(4)
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(|| anyhow!("sftp: unexpected output of ls: {}", fields.join(" ")))
+        })
+        .transpose()
+}
+
+#[async_trait]
+impl Store for Sftp {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("{}: start upload", object_id);
+        let local = Self::local_tmp_path(object_id);
+        fs::write(&local, data)
+            .await
+            .with_context(|| format!("Failed to write {}", local.display()))?;
+
+        let result = self.upload(object_id, &local).await;
+        let _ = fs::remove_file(&local).await;
+        result.with_context(|| "Failed to upload")?;
+
+        log::debug!("{}: upload completed", object_id);
+        Ok(Stored::default())
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let local = Self::local_tmp_path(object_id);
+
+        let result = self.download(object_id, &local).await;
+        let _ = fs::remove_file(&local).await;
+        let data = result.with_context(|| "Failed to download")?;
+
+        log::debug!("{}: download completed", object_id);
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        // fails, and lists nothing, when the object does not exist
+        let output = self
+            .batch(&format!(
+                "-ls -ln \"{}/{}\"\n",
+                self.object_dir(object_id),
+                object_id
+            ))
+            .await
+            .with_context(|| "Failed to check object")?;
+        Ok(parse_ls_long(&output, object_id)?.map(|size| Head {
+            size,
+            sha256: None,
+            etag: None,
+            retain_until: None,
+        }))
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        // the glob matches nothing, and fails, while the store is empty
+        self.batch(&format!("-ls -1 \"{}\"/*/*/*\n", self.path))
+            .await
+            .map(|output| parse_ls(&output))
+            .with_context(|| "Failed to list objects")
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        self.batch(&format!(
+            "rm \"{}/{}\"\n",
+            self.object_dir(object_id),
+            object_id
+        ))
+        .await
+        .map(|_| ())
+        .with_context(|| "Failed to delete")
+    }
+}
+
+/// The round trip runs against an OpenSSH server with `cargo test -- --ignored`; the host, which
+/// must already be known and accept the user's key, is taken from `FS2CLOUD_TEST_SFTP_HOST`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[tokio::test]
+    #[ignore]
+    async fn round_trip() {
+        let host = env::var("FS2CLOUD_TEST_SFTP_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let path = env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()));
+        let store = Sftp::new(Config {
+            host: &host,
+            port: env::var("FS2CLOUD_TEST_SFTP_PORT")
+                .ok()
+                .map(|port| port.parse().unwrap()),
+            username: None,
+            key_file: None,
+            path: path.to_str().unwrap(),
+        });
+        let object_id = Uuid::new_v4();
+
+        store
+            .batch(&format!("mkdir \"{}\"\n", store.path))
+            .await
+            .unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        store.put(object_id, "hello".as_bytes()).await.unwrap();
+        store.put(object_id, "world".as_bytes()).await.unwrap();
+
+        assert_eq!(store.get(object_id).await.unwrap(), "world".as_bytes());
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        assert_eq!(store.head(object_id).await.unwrap().unwrap().size, 5);
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        assert!(store.get(object_id).await.is_err());
+
+        let (shard, _) = shards(object_id);
+        store
+            .batch(&format!(
+                "rmdir \"{dir}\"\nrmdir \"{path}/{shard}\"\nrmdir \"{path}\"\n",
+                dir = store.object_dir(object_id),
+                path = store.path,
+                shard = shard
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn object_dir() {
+        let store = Sftp::new(Config {
+            host: "localhost",
+            port: None,
+            username: None,
+            key_file: None,
+            path: "backup/",
+        });
+        assert_eq!(
+            store.object_dir(Uuid::parse_str("abcdef01-2345-6789-abcd-ef0123456789").unwrap()),
+            "backup/ab/cd"
+        );
+    }
+
+    #[test]
+    fn ls_output() {
+        let output = "sftp> -ls -1 \"backup\"/*/*/*\n\
+                      backup/ab/cd/abcdef01-2345-6789-abcd-ef0123456789\n\
+                      backup/ab/cd/.abcdef01-2345-6789-abcd-ef0123456789.tmp\n";
+        assert_eq!(
+            parse_ls(output),
+            vec![Uuid::parse_str("abcdef01-2345-6789-abcd-ef0123456789").unwrap()]
+        );
+    }
+
+    #[test]
+    fn ls_long_output() {
+        let object_id = Uuid::parse_str("abcdef01-2345-6789-abcd-ef0123456789").unwrap();
+        let output = "sftp> -ls -ln \"backup/ab/cd/abcdef01-2345-6789-abcd-ef0123456789\"\n\
+                      -rw-r--r--    1 1000     1000         1234 Oct 18 14:39 \
+                      backup/ab/cd/abcdef01-2345-6789-abcd-ef0123456789\n";
+        assert_eq!(parse_ls_long(output, object_id).unwrap(), Some(1234));
+        assert_eq!(
+            parse_ls_long("sftp> -ls -ln \"backup/ab/cd/x\"\n", object_id).unwrap(),
+            None
+        );
+    }
+}
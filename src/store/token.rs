@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tokens are renewed this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Caches an OAuth2 bearer token until it expires.
+#[derive(Default)]
+pub struct Token {
+    cache: Mutex<Option<(String, Instant)>>,
+}
+
+impl Token {
+    /// Returns the cached token, or a new one from `fetch` if there is none or it is about to
+    /// expire.
+    pub fn get<F>(&self, fetch: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<(String, Duration)>,
+    {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((token, expires_at)) = cache.as_ref() {
+            if Instant::now() + EXPIRY_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let (token, expires_in) = fetch()?;
+        *cache = Some((token.clone(), Instant::now() + expires_in));
+        Ok(token)
+    }
+}
+
+/// Extracts the token and its lifetime from an OAuth2 token response. Some services return
+/// `expires_in` as a string.
+pub fn parse_response(json: &str) -> Result<(String, Duration)> {
+    let response: Value = serde_json::from_str(json)?;
+    let token = response["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow!("No access token in response"))?;
+    let expires_in = match &response["expires_in"] {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .unwrap_or(0);
+    Ok((token.to_string(), Duration::from_secs(expires_in)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_token_response() {
+        assert_eq!(
+            parse_response(r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#)
+                .unwrap(),
+            ("abc".to_string(), Duration::from_secs(3599))
+        );
+        assert_eq!(
+            parse_response(r#"{"access_token":"abc","expires_in":"3599"}"#).unwrap(),
+            ("abc".to_string(), Duration::from_secs(3599))
+        );
+        assert!(parse_response(r#"{"error":"invalid_grant"}"#).is_err());
+    }
+}
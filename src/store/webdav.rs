@@ -0,0 +1,314 @@
+use crate::store::{Head, Store, Stored};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use attohttpc::{Method, RequestBuilder, StatusCode};
+use native_tls::Certificate;
+use std::fs;
+use std::sync::Arc;
+use uuid::Uuid;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+pub struct Config<'a> {
+    /// URL of the collection where the objects are stored
+    pub url: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// PEM file of an additional certificate authority, e.g. for self-signed servers
+    pub ca_certificate: Option<&'a str>,
+    pub accept_invalid_certs: bool,
+}
+
+pub struct WebDav {
+    client: Arc<Client>,
+}
+
+struct Client {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    ca_certificate: Option<Certificate>,
+    accept_invalid_certs: bool,
+}
+
+impl WebDav {
+    pub fn new(config: Config) -> Result<WebDav> {
+        let ca_certificate = match config.ca_certificate {
+            None => None,
+            Some(path) => Some(
+                fs::read(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|pem| Ok(Certificate::from_pem(&pem)?))
+                    .with_context(|| format!("Failed to load certificate {}", path))?,
+            ),
+        };
+
+        Ok(WebDav {
+            client: Arc::new(Client {
+                url: format!("{}/", config.url.trim_end_matches('/')),
+                username: config.username.map(String::from),
+                password: config.password.map(String::from),
+                ca_certificate,
+                accept_invalid_certs: config.accept_invalid_certs,
+            }),
+        })
+    }
+
+    /// Runs a blocking operation of the client without blocking the runtime.
+    async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&Client) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || operation(&client)).await?
+    }
+}
+
+impl Client {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = RequestBuilder::new(method, format!("{}{}", self.url, path))
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        if let Some(certificate) = &self.ca_certificate {
+            request = request.add_root_certificate(certificate.clone());
+        }
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        request
+    }
+
+    fn put(&self, object_id: Uuid, data: Vec<u8>) -> Result<Option<String>> {
+        let path = object_id.to_string();
+        let mut response = self.request(Method::PUT, &path).bytes(&data).send()?;
+        if response.status() == StatusCode::CONFLICT {
+            // the collection does not exist yet
+            self.create_collection()?;
+            response = self.request(Method::PUT, &path).bytes(data).send()?;
+        }
+        Self::check(response.status())?;
+        Ok(response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from))
+    }
+
+    fn create_collection(&self) -> Result<()> {
+        log::info!("Creating collection {}", self.url);
+        let status = self
+            .request(Method::from_bytes(b"MKCOL")?, "")
+            .send()?
+            .status();
+        match status {
+            // already created
+            StatusCode::METHOD_NOT_ALLOWED => Ok(()),
+            status => Self::check(status).with_context(|| "Failed to create collection"),
+        }
+    }
+
+    fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        let response = self.request(Method::GET, &object_id.to_string()).send()?;
+        Self::check(response.status())?;
+        Ok(response.bytes()?)
+    }
+
+    fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        let response = self.request(Method::HEAD, &object_id.to_string()).send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Self::check(response.status())?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        Ok(Some(Head {
+            size: header("Content-Length")
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(|| anyhow!("WebDAV: no Content-Length"))?,
+            sha256: None,
+            etag: header("ETag"),
+            retain_until: None,
+        }))
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>> {
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND")?, "")
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .text(r#"<?xml version="1.0"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#)
+            .send()?;
+        match response.status() {
+            // the collection does not exist yet
+            StatusCode::NOT_FOUND => Ok(Vec::new()),
+            status => {
+                Self::check(status)?;
+                parse_multistatus(&response.text_utf8()?)
+            }
+        }
+    }
+
+    fn delete(&self, object_id: Uuid) -> Result<()> {
+        let response = self
+            .request(Method::DELETE, &object_id.to_string())
+            .send()?;
+        Self::check(response.status())
+    }
+
+    fn check(status: StatusCode) -> Result<()> {
+        match status {
+            status if status.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                bail!("WebDAV: invalid credentials ({})", status)
+            }
+            status => bail!("WebDAV: error {}", status),
+        }
+    }
+}
+
+/// Extracts the ids of the objects listed in a PROPFIND response. The collection itself and the
+/// resources that are not objects are skipped.
+fn parse_multistatus(xml: &str) -> Result<Vec<Uuid>> {
+    let mut objects = Vec::new();
+    let mut in_href = false;
+    for token in Tokenizer::from(xml) {
+        match token.map_err(|e| anyhow!("Invalid PROPFIND response: {}", e))? {
+            Token::ElementStart { local, .. } => in_href = local.as_str() == "href",
+            Token::ElementEnd {
+                end: ElementEnd::Open,
+                ..
+            } => {}
+            Token::ElementEnd { .. } => in_href = false,
+            Token::Text { text } if in_href => {
+                if let Some(object_id) = text
+                    .as_str()
+                    .trim()
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .and_then(|name| Uuid::parse_str(name).ok())
+                {
+                    objects.push(object_id);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(objects)
+}
+
+#[async_trait]
+impl Store for WebDav {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        log::debug!("{}: start upload", object_id);
+        let data = Vec::from(data);
+        let etag = self
+            .run(move |client| client.put(object_id, data))
+            .await
+            .with_context(|| "Failed to upload")?;
+        log::debug!("{}: upload completed", object_id);
+        Ok(Stored {
+            etag,
+            ..Default::default()
+        })
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        log::debug!("{}: start download", object_id);
+        let data = self
+            .run(move |client| client.get(object_id))
+            .await
+            .with_context(|| "Failed to download")?;
+        log::debug!("{}: download completed", object_id);
+        Ok(data)
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.run(move |client| client.head(object_id))
+            .await
+            .with_context(|| "Failed to check object")
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        self.run(|client| client.list())
+            .await
+            .with_context(|| "Failed to list objects")
+    }
+
+    async fn delete(&self, object_id: Uuid) -> Result<()> {
+        self.run(move |client| client.delete(object_id))
+            .await
+            .with_context(|| "Failed to delete")
+    }
+}
+
+/// The round trip runs against a local WebDAV server with `cargo test -- --ignored`, e.g.
+/// `rclone serve webdav --addr localhost:8080 /tmp/webdav`; the URL of the collection to use is
+/// taken from `FS2CLOUD_TEST_WEBDAV_URL`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[tokio::test]
+    #[ignore]
+    async fn round_trip() {
+        let url = format!(
+            "{}/{}",
+            env::var("FS2CLOUD_TEST_WEBDAV_URL")
+                .unwrap_or_else(|_| "http://localhost:8080".to_string())
+                .trim_end_matches('/'),
+            Uuid::new_v4()
+        );
+        let store = WebDav::new(Config {
+            url: &url,
+            username: env::var("FS2CLOUD_TEST_WEBDAV_USERNAME").ok().as_deref(),
+            password: env::var("FS2CLOUD_TEST_WEBDAV_PASSWORD").ok().as_deref(),
+            ca_certificate: None,
+            accept_invalid_certs: false,
+        })
+        .unwrap();
+        let object_id = Uuid::new_v4();
+
+        // the collection does not exist yet
+        assert!(store.list().await.unwrap().is_empty());
+        store.put(object_id, "hello".as_bytes()).await.unwrap();
+        store.put(object_id, "world".as_bytes()).await.unwrap();
+
+        assert_eq!(store.get(object_id).await.unwrap(), "world".as_bytes());
+        assert_eq!(store.list().await.unwrap(), vec![object_id]);
+        assert_eq!(store.head(object_id).await.unwrap().unwrap().size, 5);
+
+        store.delete(object_id).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        assert!(store.head(object_id).await.unwrap().is_none());
+        assert!(store.get(object_id).await.is_err());
+    }
+
+    #[test]
+    fn parse_propfind_response() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/fs2cloud/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/fs2cloud/abcdef01-2345-6789-abcd-ef0123456789</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>http://localhost/dav/fs2cloud/.abcdef01-2345-6789-abcd-ef0123456789.tmp</d:href>
+  </d:response>
+</d:multistatus>"#;
+
+        assert_eq!(
+            parse_multistatus(xml).unwrap(),
+            vec![Uuid::parse_str("abcdef01-2345-6789-abcd-ef0123456789").unwrap()]
+        );
+    }
+}
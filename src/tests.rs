@@ -0,0 +1,1025 @@
+//! End to end tests: crawl a folder, push it to a store and read it back with restore and mount.
+use crate::chunk::repository::Repository as ChunksRepository;
+use crate::chunk_location::repository::Repository as ChunkLocationsRepository;
+use crate::chunk_transfer::repository::Repository as ChunkTransfersRepository;
+use crate::controller::mount::Fs2CloudFS;
+use crate::controller::{backup, crawl, push, restore, tier, transfer};
+use crate::file::repository::{FailedFile, Repository as FilesRepository};
+use crate::file::Mode;
+use crate::status::Status;
+use crate::store::catalog::Catalog;
+use crate::store::chaos::{self, Chaos};
+use crate::store::memory::Memory;
+use crate::store::{Head, Store, Stored, Unsupported};
+use crate::{Config, Pgp, PooledSqliteConnectionManager};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Builder;
+use uuid::Uuid;
+
+/// The seed of the random data and faults of a test: `FS2CLOUD_TEST_SEED` to replay a failed run,
+/// random otherwise.
+fn seed() -> u64 {
+    let seed = std::env::var("FS2CLOUD_TEST_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random);
+    println!("seed: {}", seed);
+    seed
+}
+
+struct Fixture {
+    folder: PathBuf,
+    config: Config,
+}
+
+impl Fixture {
+    /// Creates a folder with a configuration, a key and a few files to back up: one large file
+    /// split in several chunks and small ones that end up in aggregates.
+    fn new() -> Fixture {
+        let folder = std::env::temp_dir().join(format!("fs2cloud-{}", Uuid::new_v4()));
+        let root = folder.join("root");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(seed());
+        let mut random_bytes = |len: usize| (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+        fs::write(root.join("large.bin"), random_bytes(2500)).unwrap();
+        fs::write(root.join("small.txt"), "hello").unwrap();
+        fs::write(root.join("a").join("small.bin"), random_bytes(300)).unwrap();
+        fs::write(
+            root.join("a").join("b").join("small.bin"),
+            random_bytes(400),
+        )
+        .unwrap();
+
+        Pgp::generate_key(&folder.join("key.pgp"), "passphrase").unwrap();
+
+        let config_path = folder.join("config.yml");
+        fs::write(
+            &config_path,
+            format!(
+                "database: {folder}/db.sqlite\n\
+                 root: {folder}/root\n\
+                 workers: 2\n\
+                 chunks:\n  size: 1KB\n\
+                 aggregate:\n  min_size: 512B\n  size: 1KB\n\
+                 pgp:\n  key: {folder}/key.pgp\n  passphrase: passphrase\n",
+                folder = folder.display()
+            ),
+        )
+        .unwrap();
+        let config = Config::new(config_path.to_str().unwrap()).unwrap();
+
+        Fixture { folder, config }
+    }
+
+    fn crawled() -> Fixture {
+        let fixture = Fixture::new();
+        fixture.crawl();
+        fixture
+    }
+
+    fn sqlite(&self) -> PooledSqliteConnectionManager {
+        PooledSqliteConnectionManager::try_from(&self.config).unwrap()
+    }
+
+    fn pgp(&self) -> Pgp {
+        Pgp::try_from(&self.config).unwrap()
+    }
+
+    fn root(&self) -> &Path {
+        Path::new(self.config.get_root_path().unwrap())
+    }
+
+    fn crawl_config(&self) -> crawl::Config<'_> {
+        crawl::Config {
+            root_folder: self.config.get_root_path().unwrap(),
+            chunk_size: self.config.get_chunk_size().get_bytes() as u64,
+            aggregate_min_size: self.config.get_aggregate_min_size().get_bytes() as u64,
+            aggregate_size: self.config.get_aggregate_size().get_bytes() as u64,
+            ignored_files: self.config.get_ignored_files().unwrap(),
+        }
+    }
+
+    fn crawl(&self) {
+        crawl::execute(self.crawl_config(), self.sqlite()).unwrap();
+    }
+
+    /// Pushes to `default`, without limits; tests change what they need.
+    fn push_config(&self) -> push::Config<'_> {
+        push::Config {
+            root_folder: self.config.get_root_path().unwrap(),
+            store: "default",
+            max_attempts: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            parallelism: push::Parallelism::default(),
+            memory_limit: None,
+            policy: push::Policy::default(),
+            quota: push::Quota::default(),
+        }
+    }
+
+    fn push(&self, store: Box<dyn Store>) {
+        self.push_with(self.push_config(), store)
+    }
+
+    fn push_to(&self, name: &str, store: Box<dyn Store>) {
+        self.push_with(
+            push::Config {
+                store: name,
+                ..self.push_config()
+            },
+            store,
+        )
+    }
+
+    fn push_with(&self, config: push::Config, store: Box<dyn Store>) {
+        push::execute(
+            config,
+            self.sqlite(),
+            self.pgp(),
+            store,
+            Builder::new_multi_thread().enable_all().build().unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn pending_chunks(&self) -> u64 {
+        ChunksRepository::new(self.sqlite())
+            .count_by_status(Status::Pending)
+            .unwrap()
+    }
+
+    fn failed_chunks(&self) -> u64 {
+        ChunksRepository::new(self.sqlite())
+            .count_by_status(Status::Failed)
+            .unwrap()
+    }
+
+    fn restore(&self, store: Box<dyn Store>) -> PathBuf {
+        let target = self.folder.join("restore");
+        restore::execute(
+            restore::Config {
+                target_folder: target.to_str().unwrap(),
+            },
+            self.sqlite(),
+            self.pgp(),
+            store,
+            Builder::new_current_thread().enable_all().build().unwrap(),
+        )
+        .unwrap();
+        target
+    }
+
+    fn transfer(&self, from: Box<dyn Store>, to: Box<dyn Store>) {
+        transfer::execute(
+            transfer::Config {
+                destination: "destination",
+                source_store: "default",
+                destination_store: "destination",
+            },
+            self.sqlite(),
+            from,
+            to,
+            Builder::new_current_thread().enable_all().build().unwrap(),
+        )
+        .unwrap();
+    }
+
+    /// The files of the root folder not restored to `target`, or restored with other content.
+    fn unrestored(&self, target: &Path) -> Vec<&'static str> {
+        ["large.bin", "small.txt", "a/small.bin", "a/b/small.bin"]
+            .into_iter()
+            .filter(|file| {
+                fs::read(target.join(file)).ok() != Some(fs::read(self.root().join(file)).unwrap())
+            })
+            .collect()
+    }
+
+    /// Asserts that all files of the root folder were restored to `target`.
+    fn assert_restored(&self, target: &Path) {
+        assert_eq!(self.unrestored(target), Vec::<&str>::new());
+    }
+
+    /// Restores from `store` and asserts that all files were.
+    fn assert_restores(&self, store: Box<dyn Store>) {
+        let target = self.restore(store);
+        self.assert_restored(&target);
+    }
+
+    fn failed_files(&self) -> Vec<FailedFile> {
+        FilesRepository::new(self.sqlite()).find_failed().unwrap()
+    }
+
+    /// Asserts that all chunks were pushed and that no file failed.
+    fn assert_pushed(&self) {
+        assert_eq!(self.pending_chunks(), 0);
+        assert!(self.failed_files().is_empty());
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.folder);
+    }
+}
+
+/// The objects held by a store, sorted.
+fn objects(store: &dyn Store) -> Vec<Uuid> {
+    let mut objects = Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(store.list())
+        .unwrap();
+    objects.sort();
+    objects
+}
+
+#[test]
+fn crawl_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_mount() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    fixture.push(Box::new(store.clone()));
+
+    let fs = Fs2CloudFS::new(
+        None,
+        fixture.sqlite(),
+        fixture.pgp(),
+        Box::new(store),
+        Builder::new_current_thread().enable_all().build().unwrap(),
+    );
+    let chunks_repository = ChunksRepository::new(fixture.sqlite());
+    let files = FilesRepository::new(fixture.sqlite())
+        .find_by_status_and_mode(Status::Done, Mode::Chunked)
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    for file in files {
+        let mut data = Vec::new();
+        for chunk in chunks_repository.find_by_file_uuid(&file.uuid).unwrap() {
+            data.extend(fs.read_from_store(&chunk).unwrap());
+        }
+        assert_eq!(data, fs::read(fixture.root().join(&file.path)).unwrap());
+    }
+}
+
+#[test]
+fn crawl_push_transfer_restore() {
+    let fixture = Fixture::crawled();
+    let source = Memory::new();
+    let destination = Memory::new();
+
+    fixture.push(Box::new(source.clone()));
+    let done = ChunksRepository::new(fixture.sqlite())
+        .count_by_status(Status::Done)
+        .unwrap();
+    let transfers = ChunkTransfersRepository::new(fixture.sqlite());
+
+    // a chunk that does not match what was pushed is not transferred
+    let connection = fixture.sqlite().get().unwrap();
+    let sha256 = |sha256: &str| {
+        connection
+            .execute(
+                "update chunk_locations set sha256 = ?1 \
+                 where chunk_uuid = (select min(chunk_uuid) from chunk_locations)",
+                [sha256],
+            )
+            .unwrap();
+    };
+    let pushed = connection
+        .query_row(
+            "select sha256 from chunk_locations order by chunk_uuid limit 1",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .unwrap();
+    sha256("0");
+    fixture.transfer(Box::new(source.clone()), Box::new(destination.clone()));
+    assert_eq!(
+        transfers.find_by_destination("destination").unwrap().len() as u64,
+        done - 1
+    );
+
+    sha256(&pushed);
+    fixture.transfer(Box::new(source), Box::new(destination.clone()));
+    assert_eq!(
+        transfers.find_by_destination("destination").unwrap().len() as u64,
+        done
+    );
+
+    // already transferred chunks are not read from the source again
+    fixture.transfer(Box::new(Memory::new()), Box::new(destination.clone()));
+
+    fixture.assert_restores(Box::new(destination));
+}
+
+#[test]
+fn crawl_push_mirror_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+    let mirror = Memory::new();
+
+    fixture.push(Box::new(store));
+    fixture.push_to("mirror", Box::new(mirror.clone()));
+
+    let chunks = ChunksRepository::new(fixture.sqlite());
+    assert_eq!(chunks.count_missing_in_store("default", None).unwrap(), 0);
+    assert_eq!(chunks.count_missing_in_store("mirror", None).unwrap(), 0);
+
+    fixture.assert_restores(Box::new(mirror));
+}
+
+#[test]
+fn crawl_push_tier_restore() {
+    let fixture = Fixture::new();
+    let hot = Memory::new();
+    let cold = Memory::new();
+    let catalog = || {
+        Catalog::new(
+            "default".into(),
+            HashMap::from([
+                ("default".into(), Box::new(hot.clone()) as Box<dyn Store>),
+                ("cold".into(), Box::new(cold.clone()) as Box<dyn Store>),
+            ]),
+            ChunkLocationsRepository::new(fixture.sqlite()),
+        )
+    };
+
+    fixture.crawl();
+    fixture.push(Box::new(hot.clone()));
+
+    // all files but the large one were last modified long ago
+    let files = FilesRepository::new(fixture.sqlite());
+    let chunks = ChunksRepository::new(fixture.sqlite());
+    for file in files.find_all().unwrap() {
+        if file.path != "large.bin" {
+            files.update_mtime(&file.uuid, 0).unwrap();
+        }
+    }
+    let large = files.find_by_path("large.bin").unwrap().unwrap();
+    let large_chunks = chunks.find_by_file_uuid(&large.uuid).unwrap();
+
+    tier::execute(
+        tier::Config {
+            rules: vec![tier::Rule {
+                from: "default",
+                to: "cold",
+                unchanged_for: Duration::from_secs(180 * 86400),
+            }],
+        },
+        fixture.sqlite(),
+        HashMap::from([
+            ("default".into(), Box::new(hot.clone()) as Box<dyn Store>),
+            ("cold".into(), Box::new(cold.clone()) as Box<dyn Store>),
+        ]),
+        Builder::new_current_thread().enable_all().build().unwrap(),
+    )
+    .unwrap();
+
+    let mut expected = large_chunks.iter().map(|c| c.uuid).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(objects(&hot), expected);
+    assert!(!objects(&cold).is_empty());
+
+    // moved chunks are not pushed again
+    fixture.push(Box::new(hot.clone()));
+    assert_eq!(objects(&hot), expected);
+
+    fixture.assert_restores(Box::new(catalog()));
+}
+
+/// Holds objects but refuses new ones, as if the link was down.
+struct ReadOnly(Memory);
+
+#[async_trait]
+impl Store for ReadOnly {
+    async fn put(&self, object_id: Uuid, _data: &[u8]) -> Result<Stored> {
+        Err(anyhow!("{}: read-only store", object_id))
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        self.0.get(object_id).await
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.0.head(object_id).await
+    }
+}
+
+#[test]
+fn crawl_push_crash_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    fixture.push(Box::new(store.clone()));
+
+    // as if the process stopped after the uploads, before recording them
+    fixture
+        .sqlite()
+        .get()
+        .unwrap()
+        .execute_batch(
+            "update chunks set status = 'PENDING';\
+             update files set status = 'PENDING';\
+             update chunk_locations set status = 'PENDING', etag = null;",
+        )
+        .unwrap();
+    assert!(fixture.pending_chunks() > 0);
+
+    // the objects already in the store are not uploaded again
+    fixture.push(Box::new(ReadOnly(store.clone())));
+    fixture.assert_pushed();
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_retry_failed_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    fixture.push(Box::new(ReadOnly(store.clone())));
+
+    let files = FilesRepository::new(fixture.sqlite());
+    let failed = files.find_failed().unwrap();
+    assert_eq!(
+        failed.len() as u64,
+        files.count_by_status(Status::Failed).unwrap()
+    );
+    assert!(!failed.is_empty());
+    assert!(failed.iter().all(|file| file.attempts == 1
+        && file.failed_at.is_some()
+        && file.error.as_ref().unwrap().contains("read-only store")));
+    // the layout of aggregates is recorded once they are pushed
+    let layouts = || -> u64 {
+        fixture
+            .sqlite()
+            .get()
+            .unwrap()
+            .query_row(
+                "select count(*) from aggregates where offset is not null",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    assert_eq!(layouts(), 0);
+
+    // files that failed too many times are left alone
+    fixture.push_with(
+        push::Config {
+            max_attempts: Some(1),
+            ..fixture.push_config()
+        },
+        Box::new(store.clone()),
+    );
+    assert!(objects(&store).is_empty());
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+    assert!(layouts() > 0);
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_change_push_crawl_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    let large = fixture.root().join("large.bin");
+    let mut data = fs::read(&large).unwrap();
+    data.extend_from_slice(&[7; 600]);
+    fs::write(&large, data).unwrap();
+
+    // the file is not pushed with a mix of old and new data
+    fixture.push(Box::new(store.clone()));
+    let failed = fixture.failed_files();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].path, "large.bin");
+    assert!(failed[0]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("Changed since crawled"));
+
+    // crawl plans its chunks again
+    fixture.crawl();
+    let files = FilesRepository::new(fixture.sqlite());
+    assert!(files.find_failed().unwrap().is_empty());
+    let file = files.find_by_path("large.bin").unwrap().unwrap();
+    assert_eq!(file.size, 3100);
+    assert_eq!(file.chunks, 4);
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_change_crawl_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    fixture.push(Box::new(store.clone()));
+    let pushed = objects(&store).len();
+
+    // as if the file changed before its last chunk was recorded
+    fixture
+        .sqlite()
+        .get()
+        .unwrap()
+        .execute(
+            "update files set status = 'PENDING' where path = 'large.bin'",
+            [],
+        )
+        .unwrap();
+    let large = fixture.root().join("large.bin");
+    let mut data = fs::read(&large).unwrap();
+    data.extend_from_slice(&[7; 600]);
+    fs::write(&large, data).unwrap();
+    fixture.crawl();
+
+    // the objects of the chunks planned again are deleted
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+    let stored = objects(&store);
+    assert_eq!(stored.len(), pushed + 1);
+    let mut located = ChunkLocationsRepository::new(fixture.sqlite())
+        .find_by_store("default")
+        .unwrap()
+        .into_iter()
+        .map(|location| location.chunk_uuid)
+        .collect::<Vec<Uuid>>();
+    located.sort();
+    assert_eq!(stored, located);
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_change_aggregated_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+    fs::write(fixture.root().join("small.txt"), "hello, world").unwrap();
+
+    // the aggregate holds the file as it is now, and its record says so
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+    let file = FilesRepository::new(fixture.sqlite())
+        .find_by_path("small.txt")
+        .unwrap()
+        .unwrap();
+    assert!(!file.changed(&fs::metadata(fixture.root().join("small.txt")).unwrap()));
+
+    fixture.assert_restores(Box::new(store));
+}
+
+/// Counts the deletions it is asked for, which it does not support.
+#[derive(Clone, Default)]
+struct Undeletable {
+    inner: Memory,
+    deletes: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Store for Undeletable {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        self.inner.put(object_id, data).await
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        self.inner.get(object_id).await
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.inner.head(object_id).await
+    }
+
+    async fn delete(&self, _object_id: Uuid) -> Result<()> {
+        self.deletes.fetch_add(1, Ordering::SeqCst);
+        Err(Unsupported("Deleting objects").into())
+    }
+}
+
+#[test]
+fn crawl_push_orphans_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Undeletable::default();
+    let orphans = || {
+        ChunkLocationsRepository::new(fixture.sqlite())
+            .find_orphans_by_store("default")
+            .unwrap()
+            .len()
+    };
+    let runtime = Builder::new_current_thread().build().unwrap();
+    // as if the chunks of these objects were planned again
+    for _ in 0..2 {
+        let uuid = Uuid::new_v4();
+        runtime.block_on(store.put(uuid, b"orphan")).unwrap();
+        fixture
+            .sqlite()
+            .get()
+            .unwrap()
+            .execute(
+                "insert into chunk_locations (chunk_uuid, store, status, size) \
+                 values (?, 'default', 'DONE', 6)",
+                [uuid.to_string()],
+            )
+            .unwrap();
+    }
+
+    // the store cannot delete them: it is asked once, and they are kept
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+    assert_eq!(store.deletes.load(Ordering::SeqCst), 1);
+    assert_eq!(orphans(), 2);
+
+    fixture.push(Box::new(store.inner.clone()));
+    assert_eq!(orphans(), 0);
+
+    fixture.assert_restores(Box::new(store.inner));
+}
+
+#[test]
+fn crawl_interrupted_push_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    let pending = fixture.pending_chunks();
+    fixture.push_with(
+        push::Config {
+            interrupted: Arc::new(AtomicBool::new(true)),
+            ..fixture.push_config()
+        },
+        Box::new(store.clone()),
+    );
+
+    // nothing was queued, nor failed
+    assert_eq!(fixture.pending_chunks(), pending);
+    assert!(fixture.failed_files().is_empty());
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+
+    fixture.assert_restores(Box::new(store));
+}
+
+/// Counts the calls the stores it is cloned into receive, and measures the uploads.
+#[derive(Clone, Default)]
+struct Instrumented {
+    inner: Memory,
+    /// added to each upload, so that they overlap
+    latency: Duration,
+    cleanups: Arc<AtomicUsize>,
+    /// bytes being uploaded, and the most at once
+    uploading: Arc<AtomicU64>,
+    peak: Arc<AtomicU64>,
+    /// objects being uploaded, and the most at once
+    uploads: Arc<AtomicUsize>,
+    peak_uploads: Arc<AtomicUsize>,
+    /// objects in the order they were uploaded
+    uploaded: Arc<Mutex<Vec<Uuid>>>,
+}
+
+#[async_trait]
+impl Store for Instrumented {
+    async fn put(&self, object_id: Uuid, data: &[u8]) -> Result<Stored> {
+        let bytes = data.len() as u64;
+        let uploading = self.uploading.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.peak.fetch_max(uploading, Ordering::SeqCst);
+        let uploads = self.uploads.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_uploads.fetch_max(uploads, Ordering::SeqCst);
+        tokio::time::sleep(self.latency).await;
+        let stored = self.inner.put(object_id, data).await;
+        self.uploads.fetch_sub(1, Ordering::SeqCst);
+        self.uploading.fetch_sub(bytes, Ordering::SeqCst);
+        self.uploaded.lock().unwrap().push(object_id);
+        stored
+    }
+
+    async fn get(&self, object_id: Uuid) -> Result<Vec<u8>> {
+        self.inner.get(object_id).await
+    }
+
+    async fn head(&self, object_id: Uuid) -> Result<Option<Head>> {
+        self.inner.head(object_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        self.inner.list().await
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        self.cleanups.fetch_add(1, Ordering::SeqCst);
+        self.inner.cleanup().await
+    }
+}
+
+#[test]
+fn backup_backup_restore() {
+    let fixture = Fixture::new();
+    let store = Instrumented::default();
+    // enough small files for some aggregates to be full before crawl ends
+    for i in 0..4 {
+        fs::write(fixture.root().join(format!("{}.txt", i)), vec![b'a'; 400]).unwrap();
+    }
+    let backup = || {
+        backup::execute(
+            backup::Config {
+                crawl: fixture.crawl_config(),
+                push: fixture.push_config(),
+            },
+            fixture.sqlite(),
+            fixture.pgp(),
+            Box::new(store.clone()),
+            Builder::new_multi_thread().enable_all().build().unwrap(),
+        )
+        .unwrap();
+    };
+
+    backup();
+    fixture.assert_pushed();
+    // once, before streaming
+    assert_eq!(store.cleanups.load(Ordering::SeqCst), 1);
+    let pushed = objects(&store);
+    assert!(!pushed.is_empty());
+
+    // nothing changed, nothing is pushed again
+    backup();
+    assert_eq!(objects(&store), pushed);
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_restore_with_memory_limit() {
+    let fixture = Fixture::new();
+    let instrumented = || Instrumented {
+        latency: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let push = |name: &str, store: &Instrumented, memory_limit: Option<u64>| {
+        fixture.push_with(
+            push::Config {
+                store: name,
+                memory_limit,
+                ..fixture.push_config()
+            },
+            Box::new(store.clone()),
+        );
+    };
+    let largest = |store: &Instrumented| {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        objects(store)
+            .into_iter()
+            .map(|uuid| runtime.block_on(store.get(uuid)).unwrap().len() as u64)
+            .max()
+            .unwrap()
+    };
+
+    fixture.crawl();
+    // without limit, uploads overlap
+    let unlimited = instrumented();
+    push("mirror", &unlimited, None);
+    assert!(unlimited.peak_uploads.load(Ordering::SeqCst) > 1);
+
+    // smaller than a chunk: chunks go through the pipeline one at a time
+    let store = instrumented();
+    push("default", &store, Some(512));
+    fixture.assert_pushed();
+    assert_eq!(store.peak_uploads.load(Ordering::SeqCst), 1);
+    assert_eq!(store.peak.load(Ordering::SeqCst), largest(&store));
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_restore_in_order() {
+    let fixture = Fixture::new();
+    let store = Instrumented::default();
+    // crawled after large.bin, but more recently modified
+    fs::write(fixture.root().join("z.bin"), vec![7; 1500]).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(fixture.root().join("large.bin"))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+
+    fixture.crawl();
+    let mut priorities = globset::GlobSetBuilder::new();
+    priorities.add(globset::Glob::new("a/**").unwrap());
+    fixture.push_with(
+        push::Config {
+            // one chunk at a time, so that they are uploaded in the order they are queued
+            parallelism: push::Parallelism {
+                readers: 1,
+                encryptors: 1,
+                uploaders: 1,
+                finalizers: 1,
+                queue_size: 1,
+            },
+            policy: push::Policy {
+                order: push::Order::Newest,
+                priorities: priorities.build().unwrap(),
+            },
+            ..fixture.push_config()
+        },
+        Box::new(store.clone()),
+    );
+    fixture.assert_pushed();
+
+    // the aggregate holding a/small.bin first, then the newest file
+    let connection = fixture.sqlite().get().unwrap();
+    let uploaded = store
+        .uploaded
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|uuid| {
+            connection
+                .query_row(
+                    "select f.path, f.mode, c.idx \
+                     from chunks c join files f on f.uuid = c.file_uuid \
+                     where c.uuid = ?",
+                    [uuid.to_string()],
+                    |row| {
+                        Ok(match row.get::<_, Mode>(1)? {
+                            Mode::Aggregate => "aggregate".to_string(),
+                            _ => format!("{}:{}", row.get::<_, String>(0)?, row.get::<_, u64>(2)?),
+                        })
+                    },
+                )
+                .unwrap()
+        })
+        .collect::<Vec<String>>();
+    assert_eq!(
+        uploaded,
+        vec![
+            "aggregate",
+            "z.bin:0",
+            "z.bin:1",
+            "large.bin:0",
+            "large.bin:1",
+            "large.bin:2"
+        ]
+    );
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_with_quota_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    let pending = fixture.pending_chunks();
+    let push_at_most = |bytes: u64, store: Box<dyn Store>| {
+        fixture.push_with(
+            push::Config {
+                quota: push::Quota {
+                    bytes: Some(bytes),
+                    duration: None,
+                },
+                ..fixture.push_config()
+            },
+            store,
+        );
+    };
+
+    // only the first chunk fits, the rest is left for the next push
+    push_at_most(1024, Box::new(store.clone()));
+    assert_eq!(fixture.pending_chunks(), pending - 1);
+    assert!(fixture.failed_files().is_empty());
+
+    // smaller than a chunk: one is pushed anyway
+    push_at_most(1, Box::new(store.clone()));
+    assert_eq!(fixture.pending_chunks(), pending - 2);
+
+    // the chunks that fail to upload do not count
+    push_at_most(1, Box::new(ReadOnly(store.clone())));
+    assert_eq!(fixture.failed_chunks(), pending - 2);
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+
+    // nor do the ones already in the store
+    fixture
+        .sqlite()
+        .get()
+        .unwrap()
+        .execute_batch(
+            "update chunks set status = 'PENDING';\
+             update files set status = 'PENDING';\
+             update chunk_locations set status = 'PENDING', etag = null;",
+        )
+        .unwrap();
+    push_at_most(1, Box::new(ReadOnly(store.clone())));
+    fixture.assert_pushed();
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_dry_run_push_restore() {
+    let fixture = Fixture::crawled();
+    let store = Memory::new();
+
+    let plan = push::dry_run(fixture.push_config(), fixture.sqlite()).unwrap();
+    assert_eq!(plan.chunks, fixture.pending_chunks());
+    assert_eq!(plan.files, 4);
+    assert_eq!(plan.unreadable, 0);
+
+    fs::rename(
+        fixture.root().join("small.txt"),
+        fixture.folder.join("small.txt"),
+    )
+    .unwrap();
+    let partial = push::dry_run(fixture.push_config(), fixture.sqlite()).unwrap();
+    assert_eq!(partial.unreadable, 1);
+    assert_eq!(partial.chunks + 1, plan.chunks);
+    fs::rename(
+        fixture.folder.join("small.txt"),
+        fixture.root().join("small.txt"),
+    )
+    .unwrap();
+
+    // nothing was pushed
+    assert_eq!(plan.chunks, fixture.pending_chunks());
+
+    fixture.push(Box::new(store.clone()));
+    fixture.assert_pushed();
+    let pushed = ChunksRepository::new(fixture.sqlite())
+        .find_by_status(Status::Done)
+        .unwrap();
+    assert_eq!(
+        plan.bytes,
+        pushed.iter().map(|chunk| chunk.payload_size).sum::<u64>()
+    );
+
+    fixture.assert_restores(Box::new(store));
+}
+
+#[test]
+fn crawl_push_restore_with_faults() {
+    let fixture = Fixture::new();
+    let store = Memory::new();
+    // each run gets its own faults, all derived from the test's seed
+    let seeds = Cell::new(seed());
+    let chaos = || {
+        seeds.set(seeds.get().wrapping_add(1));
+        Box::new(Chaos::new(
+            Box::new(store.clone()),
+            chaos::Config {
+                latency: Duration::from_millis(1),
+                error_rate: 0.1,
+                forbidden_rate: 0.05,
+                truncate_rate: 0.1,
+                corrupt_rate: 0.1,
+                seed: Some(seeds.get()),
+            },
+        ))
+    };
+
+    fixture.crawl();
+    for _ in 0..50 {
+        fixture.push(chaos());
+        if fixture.pending_chunks() == 0 && fixture.failed_chunks() == 0 {
+            break;
+        }
+    }
+    assert_eq!(fixture.pending_chunks(), 0);
+    assert_eq!(fixture.failed_chunks(), 0);
+
+    // faulty reads are detected and the affected files restored on a later run
+    let mut target = fixture.restore(chaos());
+    for _ in 1..50 {
+        if fixture.unrestored(&target).is_empty() {
+            break;
+        }
+        target = fixture.restore(chaos());
+    }
+    fixture.assert_restored(&target);
+}